@@ -0,0 +1,153 @@
+// Vazão e CPU do proxy repassando TCP puro, com cópia em buffer e com splice.
+//
+//     cargo bench --bench relay
+//
+// RUSTYPROXY_BENCH_MB define quantos MiB cada conexão envia (padrão 512).
+
+#[cfg(target_os = "linux")]
+fn main() {
+    let mib_per_stream = std::env::var("RUSTYPROXY_BENCH_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(512);
+    println!(
+        "{} conexões x {} MiB através do proxy",
+        bench::STREAMS,
+        mib_per_stream
+    );
+    for splice in [false, true] {
+        bench::run(splice, mib_per_stream);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("benchmark disponível apenas no Linux (splice)");
+}
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    pub const STREAMS: usize = 4;
+    const CHUNK: usize = 64 * 1024;
+
+    pub fn run(splice: bool, mib_per_stream: usize) {
+        let received = Arc::new(AtomicU64::new(0));
+        let backend_port = start_sink(received.clone());
+        let proxy_port = free_port();
+        let mut proxy = start_proxy(splice, proxy_port, backend_port);
+
+        let cpu_before = cpu_seconds(&proxy);
+        let started = Instant::now();
+        let clients: Vec<_> = (0..STREAMS)
+            .map(|_| thread::spawn(move || send(proxy_port, mib_per_stream * 1024 * 1024)))
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        let cpu = cpu_seconds(&proxy) - cpu_before;
+        let _ = proxy.kill();
+        let _ = proxy.wait();
+
+        let total = (STREAMS * mib_per_stream * 1024 * 1024) as u64;
+        assert!(
+            received.load(Ordering::Relaxed) >= total,
+            "o backend não recebeu todos os bytes"
+        );
+        let gib = total as f64 / (1024.0 * 1024.0 * 1024.0);
+        println!(
+            "{:<8} {:>9.1} MiB/s  {:>6.2} s de CPU  {:>6.3} s de CPU por GiB",
+            if splice { "splice" } else { "buffer" },
+            total as f64 / (1024.0 * 1024.0) / elapsed,
+            cpu,
+            cpu / gib
+        );
+    }
+
+    // Backend que descarta tudo e fecha ao receber EOF.
+    fn start_sink(received: Arc<AtomicU64>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let received = received.clone();
+                thread::spawn(move || {
+                    let mut buffer = vec![0; CHUNK];
+                    while let Ok(n) = stream.read(&mut buffer) {
+                        if n == 0 {
+                            break;
+                        }
+                        received.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn start_proxy(splice: bool, port: u16, backend_port: u16) -> Child {
+        let config = std::env::temp_dir().join(format!("rustyproxy-bench-{}.toml", port));
+        fs::write(
+            &config,
+            format!(
+                "splice = {}\ndefault_backend = \"sink\"\n\n[[listeners]]\nport = {}\n\n\
+                 [log]\nlevel = \"warn\"\n\n[backends]\nsink = \"127.0.0.1:{}\"\n",
+                splice, port, backend_port
+            ),
+        )
+        .unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rustyproxy"))
+            .arg("--config")
+            .arg(&config)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return child;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("o proxy não abriu a porta {}", port);
+    }
+
+    fn send(port: u16, bytes: usize) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let chunk = vec![0x5a; CHUNK];
+        let mut sent = 0;
+        while sent < bytes {
+            let n = CHUNK.min(bytes - sent);
+            stream.write_all(&chunk[..n]).unwrap();
+            sent += n;
+        }
+        stream.shutdown(Shutdown::Write).unwrap();
+        // O backend só fecha depois de ler tudo; o EOF aqui marca o fim da transferência.
+        let _ = stream.read(&mut [0; 1]);
+    }
+
+    fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    // utime + stime do processo, em segundos.
+    fn cpu_seconds(child: &Child) -> f64 {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", child.id())).unwrap();
+        let fields: Vec<&str> = stat.rsplit(") ").next().unwrap().split(' ').collect();
+        let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+        ticks as f64 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64
+    }
+}
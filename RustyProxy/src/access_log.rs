@@ -0,0 +1,25 @@
+use crate::session::{CloseReason, Session};
+use std::sync::atomic::Ordering;
+use tracing::info;
+
+pub const TARGET: &str = "access";
+
+// Uma linha por conexão encerrada; o destino é configurado em logging::init.
+pub fn record(session: &Session, reason: CloseReason) {
+    let protocol = session
+        .protocol()
+        .map(|protocol| format!("{:?}", protocol).to_lowercase());
+    info!(
+        target: TARGET,
+        parent: None,
+        client = %session.peer.ip().to_canonical(),
+        listener = session.port,
+        user = session.user().unwrap_or("-"),
+        backend = session.backend().unwrap_or("-"),
+        protocol = protocol.as_deref().unwrap_or("-"),
+        bytes_up = session.bytes_up.load(Ordering::Relaxed),
+        bytes_down = session.bytes_down.load(Ordering::Relaxed),
+        duration_ms = session.started.elapsed().as_millis() as u64,
+        reason = %reason,
+    );
+}
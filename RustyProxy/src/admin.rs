@@ -0,0 +1,204 @@
+use crate::config::invalid;
+use crate::handshake;
+use crate::limits::AcceptBackoff;
+use crate::session::Sessions;
+use serde::Serialize;
+use serde_json::json;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::time::{self, Duration};
+use tracing::{debug, info};
+
+const REQUEST_MAX_LEN: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+pub enum AdminListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[derive(Serialize)]
+struct SessionInfo<'a> {
+    id: u64,
+    peer: String,
+    listener: u16,
+    user: Option<&'a str>,
+    backend: Option<&'a str>,
+    protocol: Option<String>,
+    bytes_up: u64,
+    bytes_down: u64,
+    buffer_bytes: i64,
+    age_ms: u64,
+}
+
+// Caminhos absolutos viram socket Unix; o resto precisa ser um endereço de loopback,
+// já que a API não tem autenticação.
+pub fn parse_addr(value: &str) -> Result<AdminAddr, Error> {
+    if value.starts_with('/') {
+        return Ok(AdminAddr::Unix(PathBuf::from(value)));
+    }
+    let addr: SocketAddr = value
+        .parse()
+        .map_err(|_| invalid(format!("--admin-listen: endereço inválido '{}'", value)))?;
+    if !addr.ip().is_loopback() {
+        return Err(invalid(format!(
+            "--admin-listen: '{}' não é um endereço de loopback",
+            value
+        )));
+    }
+    Ok(AdminAddr::Tcp(addr))
+}
+
+pub async fn bind(addr: &AdminAddr) -> Result<AdminListener, Error> {
+    match addr {
+        AdminAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!(%addr, "API de administração disponível");
+            Ok(AdminListener::Tcp(listener))
+        }
+        AdminAddr::Unix(path) => {
+            // Um socket deixado por uma execução anterior impediria o bind; qualquer outro
+            // arquivo no caminho é provavelmente um engano e fica intacto.
+            match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+                Ok(_) => {
+                    return Err(invalid(format!(
+                        "--admin-listen: {} já existe e não é um socket",
+                        path.display()
+                    )))
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listener = UnixListener::bind(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            info!(path = %path.display(), "API de administração disponível");
+            Ok(AdminListener::Unix(listener))
+        }
+    }
+}
+
+pub async fn serve(listener: AdminListener, sessions: Arc<Sessions>) {
+    let mut backoff = AcceptBackoff::new();
+    loop {
+        let accepted = match &listener {
+            AdminListener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| spawn_request(stream, sessions.clone())),
+            AdminListener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| spawn_request(stream, sessions.clone())),
+        };
+        match accepted {
+            Ok(()) => backoff.reset(),
+            Err(e) => backoff.wait(&e, "admin").await,
+        }
+    }
+}
+
+fn spawn_request<S>(stream: S, sessions: Arc<Sessions>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // O prazo vale para a requisição inteira, não só para a primeira leitura.
+    tokio::spawn(async move {
+        match time::timeout(REQUEST_TIMEOUT, handle_request(stream, &sessions)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!(error = %e, "Erro ao responder requisição de administração"),
+            Err(_) => debug!("Tempo limite excedido na requisição de administração"),
+        }
+    });
+}
+
+async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    sessions: &Sessions,
+) -> Result<(), Error> {
+    let request = handshake::read_handshake(&mut stream, REQUEST_MAX_LEN, REQUEST_TIMEOUT).await?;
+    let method = request.method.as_deref().unwrap_or_default();
+    let path = request.path.as_deref().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        ("GET", "/sessions") => ("200 OK", list_sessions(sessions)),
+        ("DELETE", path) => match path.strip_prefix("/sessions") {
+            Some(rest) => kill_sessions(sessions, rest),
+            None => not_found(),
+        },
+        _ => not_found(),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn list_sessions(sessions: &Sessions) -> String {
+    let sessions = sessions.list();
+    let infos: Vec<SessionInfo> = sessions
+        .iter()
+        .map(|session| SessionInfo {
+            id: session.id,
+            peer: session.peer.ip().to_canonical().to_string(),
+            listener: session.port,
+            user: session.user(),
+            backend: session.backend(),
+            protocol: session
+                .protocol()
+                .map(|protocol| format!("{:?}", protocol).to_lowercase()),
+            bytes_up: session.bytes_up.load(Ordering::Relaxed),
+            bytes_down: session.bytes_down.load(Ordering::Relaxed),
+            buffer_bytes: session.buffer_bytes.load(Ordering::Relaxed),
+            age_ms: session.started.elapsed().as_millis() as u64,
+        })
+        .collect();
+    serde_json::to_string(&infos).unwrap_or_default()
+}
+
+// DELETE /sessions/<id> encerra uma sessão; DELETE /sessions?peer=<ip> todas as do IP.
+fn kill_sessions(sessions: &Sessions, rest: &str) -> (&'static str, String) {
+    let killed = if let Some(id) = rest.strip_prefix('/') {
+        match id.parse() {
+            Ok(id) if sessions.kill(id) => 1,
+            _ => return not_found(),
+        }
+    } else if let Some(peer) = rest.strip_prefix("?peer=") {
+        match peer.parse::<IpAddr>() {
+            Ok(ip) => sessions.kill_peer(ip),
+            Err(_) => {
+                return (
+                    "400 Bad Request",
+                    json!({ "error": "IP inválido" }).to_string(),
+                )
+            }
+        }
+    } else {
+        return not_found();
+    };
+    info!(killed, "Sessões encerradas pela API de administração");
+    ("200 OK", json!({ "killed": killed }).to_string())
+}
+
+fn not_found() -> (&'static str, String) {
+    (
+        "404 Not Found",
+        json!({ "error": "não encontrado" }).to_string(),
+    )
+}
@@ -0,0 +1,523 @@
+use crate::config::{invalid, AuthConfig, AuthMethod, RejectMode};
+use crate::handshake::Handshake;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Error;
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
+
+// bcrypt ocupa uma thread de bloqueio por tentativa e segue rodando mesmo depois que o
+// prazo do handshake descarta a conexão; este limite segura o custo de tentativas falsas.
+static BCRYPT_SLOTS: Semaphore = Semaphore::const_new(4);
+
+// Usuário com a senha já interpretada.
+pub struct Credential {
+    pub name: String,
+    secret: Secret,
+    // SHA-1 da última senha aceita, para não refazer o bcrypt a cada conexão.
+    verified: Mutex<Option<[u8; 20]>>,
+}
+
+enum Secret {
+    Plain(String),
+    Sha1([u8; 20]),
+    Bcrypt(String),
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credential")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credential {
+    async fn verify(&self, password: &str) -> bool {
+        match &self.secret {
+            Secret::Plain(expected) => constant_time_eq(password.as_bytes(), expected.as_bytes()),
+            Secret::Sha1(expected) => constant_time_eq(&sha1(password), expected),
+            Secret::Bcrypt(hash) => {
+                let digest = sha1(password);
+                if let Some(verified) = *self.verified.lock().unwrap() {
+                    if constant_time_eq(&digest, &verified) {
+                        return true;
+                    }
+                }
+                let valid = bcrypt_verify(password, hash).await;
+                if valid {
+                    *self.verified.lock().unwrap() = Some(digest);
+                }
+                valid
+            }
+        }
+    }
+
+    fn bcrypt_hash(&self) -> Option<&str> {
+        match &self.secret {
+            Secret::Bcrypt(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    // Tokens não levam o nome do usuário, então exigiriam um bcrypt por usuário.
+    fn accepts_token(&self, token: &str) -> bool {
+        match &self.secret {
+            Secret::Plain(expected) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+            Secret::Sha1(expected) => constant_time_eq(&sha1(token), expected),
+            Secret::Bcrypt(_) => false,
+        }
+    }
+}
+
+// Junta os usuários de [auth] com os do htpasswd; uma entrada sem password em [auth]
+// usa a senha do htpasswd.
+pub fn load_credentials(config: &AuthConfig) -> Result<Vec<Credential>, Error> {
+    let mut htpasswd = BTreeMap::new();
+    if let Some(path) = &config.htpasswd {
+        let content = fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("não foi possível ler {}: {}", path.display(), e),
+            )
+        })?;
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, hash) = line.split_once(':').ok_or_else(|| {
+                invalid(format!(
+                    "{}:{}: esperado usuário:senha",
+                    path.display(),
+                    i + 1
+                ))
+            })?;
+            if htpasswd
+                .insert(name.to_string(), hash.to_string())
+                .is_some()
+            {
+                return Err(invalid(format!(
+                    "{}:{}: usuário '{}' repetido",
+                    path.display(),
+                    i + 1,
+                    name
+                )));
+            }
+        }
+    }
+
+    let mut credentials = Vec::new();
+    for (i, user) in config.users.iter().enumerate() {
+        let field = format!("auth.users[{}]", i);
+        if credentials
+            .iter()
+            .any(|credential: &Credential| credential.name == user.name)
+        {
+            return Err(invalid(format!(
+                "{}: usuário '{}' repetido",
+                field, user.name
+            )));
+        }
+        let password = match (&user.password, htpasswd.remove(&user.name)) {
+            (Some(_), Some(_)) => {
+                return Err(invalid(format!(
+                    "{}: usuário '{}' também está no htpasswd; remova o password de um dos dois",
+                    field, user.name
+                )))
+            }
+            (Some(password), None) => password.clone(),
+            (None, Some(hash)) => hash,
+            (None, None) => {
+                return Err(invalid(format!(
+                    "{}: usuário '{}' sem password e fora do htpasswd",
+                    field, user.name
+                )))
+            }
+        };
+        credentials.push(credential(&field, &user.name, &password)?);
+    }
+    for (name, hash) in htpasswd {
+        credentials.push(credential("auth.htpasswd", &name, &hash)?);
+    }
+
+    if credentials.is_empty() {
+        return Err(invalid("auth: nenhum usuário em users nem no htpasswd"));
+    }
+    Ok(credentials)
+}
+
+// Usuário autenticado pelo primeiro método de auth.methods que trouxer credenciais válidas.
+pub async fn authenticate<'a>(config: &'a AuthConfig, handshake: &Handshake) -> Option<&'a str> {
+    for method in &config.methods {
+        let credential = match method {
+            AuthMethod::Basic => match basic_credentials(handshake) {
+                Some((name, password)) => verify_basic(config, &name, &password).await,
+                None => None,
+            },
+            AuthMethod::Header => handshake
+                .header(&config.header)
+                .and_then(|token| find_by_token(config, token)),
+            AuthMethod::Path => handshake
+                .path
+                .as_deref()
+                .and_then(path_token)
+                .and_then(|token| find_by_token(config, token)),
+        };
+        if let Some(credential) = credential {
+            return Some(&credential.name);
+        }
+    }
+    None
+}
+
+async fn verify_basic<'a>(
+    config: &'a AuthConfig,
+    name: &str,
+    password: &str,
+) -> Option<&'a Credential> {
+    match config.credentials.iter().find(|c| c.name == name) {
+        Some(credential) => credential.verify(password).await.then_some(credential),
+        None => {
+            // Usuário inexistente custa o mesmo que uma senha errada, para a resposta não
+            // revelar quais nomes existem.
+            if let Some(hash) = config.credentials.iter().find_map(Credential::bcrypt_hash) {
+                bcrypt_verify(password, hash).await;
+            }
+            None
+        }
+    }
+}
+
+// Resposta enviada antes de fechar; None com reject = "close".
+pub fn rejection(config: &AuthConfig) -> Option<String> {
+    if config.reject != RejectMode::Http {
+        return None;
+    }
+    let mut response = format!("HTTP/1.1 {}\r\n", config.reject_status);
+    if config.methods.contains(&AuthMethod::Basic) {
+        response.push_str(&format!(
+            "Proxy-Authenticate: Basic realm=\"{}\"\r\n",
+            config.realm
+        ));
+    }
+    response.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    Some(response)
+}
+
+fn credential(field: &str, name: &str, password: &str) -> Result<Credential, Error> {
+    if name.is_empty() || name.contains([':', '\r', '\n']) {
+        return Err(invalid(format!(
+            "{}: nome de usuário inválido '{}'",
+            field, name
+        )));
+    }
+    let secret = if let Some(encoded) = password.strip_prefix("{SHA}") {
+        let digest = BASE64
+            .decode(encoded)
+            .ok()
+            .and_then(|digest| <[u8; 20]>::try_from(digest).ok())
+            .ok_or_else(|| invalid(format!("{}: hash {{SHA}} inválido para '{}'", field, name)))?;
+        Secret::Sha1(digest)
+    } else if ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|prefix| password.starts_with(prefix))
+    {
+        Secret::Bcrypt(password.to_string())
+    } else if password.starts_with('$') {
+        return Err(invalid(format!(
+            "{}: formato de senha não suportado para '{}'; use bcrypt (htpasswd -B), {{SHA}} (htpasswd -s) ou texto",
+            field, name
+        )));
+    } else if password.is_empty() {
+        return Err(invalid(format!("{}: senha vazia para '{}'", field, name)));
+    } else {
+        Secret::Plain(password.to_string())
+    };
+    Ok(Credential {
+        name: name.to_string(),
+        secret,
+        verified: Mutex::new(None),
+    })
+}
+
+// bcrypt é lento de propósito; roda fora das threads do runtime e a vaga só é liberada
+// quando a verificação termina.
+async fn bcrypt_verify(password: &str, hash: &str) -> bool {
+    let Ok(permit) = BCRYPT_SLOTS.acquire().await else {
+        return false;
+    };
+    let (password, hash) = (password.to_string(), hash.to_string());
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        bcrypt::verify(password, &hash)
+    })
+    .await
+    .is_ok_and(|result| result.unwrap_or(false))
+}
+
+fn basic_credentials(handshake: &Handshake) -> Option<(String, String)> {
+    let value = handshake.header("Proxy-Authorization")?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+fn find_by_token<'a>(config: &'a AuthConfig, token: &str) -> Option<&'a Credential> {
+    config
+        .credentials
+        .iter()
+        .find(|credential| credential.accepts_token(token))
+}
+
+// Primeiro segmento do caminho: "/<token>" ou "/<token>/...".
+fn path_token(path: &str) -> Option<&str> {
+    let path = path.split(['?', '#']).next()?;
+    let token = path.strip_prefix('/')?.split('/').next()?;
+    (!token.is_empty()).then_some(token)
+}
+
+fn sha1(value: &str) -> [u8; 20] {
+    Sha1::digest(value.as_bytes()).into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::{self, Handshake};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::time::Duration;
+
+    // Cada htpasswd ganha um arquivo próprio, já que os testes rodam em paralelo.
+    fn auth(toml: &str, htpasswd: Option<&str>) -> Result<AuthConfig, Error> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let mut config: AuthConfig = toml::from_str(toml).unwrap();
+        if let Some(content) = htpasswd {
+            let path = std::env::temp_dir().join(format!(
+                "rustyproxy-htpasswd-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::write(&path, content).unwrap();
+            config.htpasswd = Some(path);
+        }
+        config.credentials = load_credentials(&config)?;
+        Ok(config)
+    }
+
+    fn credential_of<'a>(config: &'a AuthConfig, name: &str) -> &'a Credential {
+        config.credentials.iter().find(|c| c.name == name).unwrap()
+    }
+
+    fn bcrypt_hash(password: &str) -> String {
+        bcrypt::hash_with_result(password, 4)
+            .unwrap()
+            .format_for_version(bcrypt::Version::TwoY)
+    }
+
+    async fn handshake(head: &str) -> Handshake {
+        handshake::read_handshake(&mut head.as_bytes(), 8192, Duration::from_secs(1))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn htpasswd_formats() {
+        let htpasswd = format!(
+            "# comentário\n\nana:texto\nbia:{{SHA}}{}\ncaio:{}\n",
+            BASE64.encode(sha1("segredo")),
+            bcrypt_hash("forte")
+        );
+        let config = auth("", Some(&htpasswd)).unwrap();
+        assert_eq!(config.credentials.len(), 3);
+        assert!(credential_of(&config, "ana").verify("texto").await);
+        assert!(!credential_of(&config, "ana").verify("Texto").await);
+        assert!(credential_of(&config, "bia").verify("segredo").await);
+        assert!(!credential_of(&config, "bia").verify("errado").await);
+        let caio = credential_of(&config, "caio");
+        assert!(!caio.verify("fraco").await);
+        assert!(caio.verify("forte").await);
+        // A segunda verificação sai do cache, sem bcrypt.
+        assert!(caio.verify("forte").await);
+
+        // Entrada de [auth] sem password usa a senha do htpasswd.
+        let config = auth(
+            "[[users]]\nname = \"ana\"\nquota_gib = 1.0",
+            Some("ana:texto\n"),
+        )
+        .unwrap();
+        assert!(credential_of(&config, "ana").verify("texto").await);
+    }
+
+    #[test]
+    fn htpasswd_errors() {
+        let error =
+            |toml: &str, htpasswd: Option<&str>| auth(toml, htpasswd).err().unwrap().to_string();
+        assert!(error("", Some("ana:a\nana:b\n")).contains("'ana' repetido"));
+        assert!(error("", Some("ana\n")).contains("esperado usuário:senha"));
+        assert!(error("", Some("ana:$apr1$abc$def\n")).contains("não suportado"));
+        assert!(error("", Some("ana:$1$abc$def\n")).contains("não suportado"));
+        assert!(error("", Some("ana:{SHA}curto\n")).contains("{SHA} inválido"));
+        assert!(error("", Some("ana:\n")).contains("senha vazia"));
+        assert!(error(
+            "[[users]]\nname = \"ana\"\npassword = \"x\"",
+            Some("ana:y\n")
+        )
+        .contains("também está no htpasswd"));
+        assert!(error("[[users]]\nname = \"ana\"", None).contains("sem password"));
+        assert!(error("", None).contains("nenhum usuário"));
+
+        for prefix in ["$2a$", "$2b$", "$2y$"] {
+            let config = auth("", Some(&format!("ana:{}05$hash\n", prefix))).unwrap();
+            assert!(credential_of(&config, "ana").bcrypt_hash().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn basic_credentials_decoding() {
+        let with = |value: &str| {
+            format!(
+                "CONNECT x:22 HTTP/1.1\r\nProxy-Authorization: {}\r\n\r\n",
+                value
+            )
+        };
+        let encoded = BASE64.encode("ana:se:nha");
+        assert_eq!(
+            basic_credentials(&handshake(&with(&format!("Basic {}", encoded))).await),
+            Some(("ana".to_string(), "se:nha".to_string()))
+        );
+        assert_eq!(
+            basic_credentials(&handshake(&with(&format!("basic  {}", encoded))).await),
+            Some(("ana".to_string(), "se:nha".to_string()))
+        );
+        assert_eq!(
+            basic_credentials(&handshake(&with(&format!("Bearer {}", encoded))).await),
+            None
+        );
+        assert_eq!(
+            basic_credentials(&handshake(&with("Basic !!!")).await),
+            None
+        );
+        assert_eq!(
+            basic_credentials(&handshake(&with(&format!("Basic {}", BASE64.encode("ana")))).await),
+            None
+        );
+        assert_eq!(
+            basic_credentials(&handshake("CONNECT x:22 HTTP/1.1\r\n\r\n").await),
+            None
+        );
+    }
+
+    #[test]
+    fn path_tokens() {
+        assert_eq!(path_token("/abc"), Some("abc"));
+        assert_eq!(path_token("/abc/ws?x=1"), Some("abc"));
+        assert_eq!(path_token("/abc?x=1"), Some("abc"));
+        assert_eq!(path_token("/abc#frag"), Some("abc"));
+        assert_eq!(path_token("/"), None);
+        assert_eq!(path_token("//abc"), None);
+        assert_eq!(path_token("abc"), None);
+    }
+
+    #[tokio::test]
+    async fn tokens_skip_bcrypt_users() {
+        let htpasswd = format!(
+            "ana:texto\nbia:{{SHA}}{}\ncaio:{}\n",
+            BASE64.encode(sha1("segredo")),
+            bcrypt_hash("forte")
+        );
+        let config = auth("methods = [\"header\", \"path\"]", Some(&htpasswd)).unwrap();
+        assert!(credential_of(&config, "ana").accepts_token("texto"));
+        assert!(credential_of(&config, "bia").accepts_token("segredo"));
+        assert!(!credential_of(&config, "caio").accepts_token("forte"));
+        assert_eq!(
+            find_by_token(&config, "segredo").map(|c| c.name.as_str()),
+            Some("bia")
+        );
+        assert!(find_by_token(&config, "forte").is_none());
+
+        let header = handshake("GET / HTTP/1.1\r\nX-Auth-Token: texto\r\n\r\n").await;
+        assert_eq!(authenticate(&config, &header).await, Some("ana"));
+        let path = handshake("GET /segredo/ws HTTP/1.1\r\n\r\n").await;
+        assert_eq!(authenticate(&config, &path).await, Some("bia"));
+        let path = handshake("GET /forte HTTP/1.1\r\n\r\n").await;
+        assert_eq!(authenticate(&config, &path).await, None);
+    }
+
+    #[tokio::test]
+    async fn basic_authentication() {
+        let htpasswd = format!("ana:texto\ncaio:{}\n", bcrypt_hash("forte"));
+        let config = auth("", Some(&htpasswd)).unwrap();
+        let basic = |credentials: &str| {
+            format!(
+                "CONNECT x:22 HTTP/1.1\r\nProxy-Authorization: Basic {}\r\n\r\n",
+                BASE64.encode(credentials)
+            )
+        };
+        assert_eq!(
+            authenticate(&config, &handshake(&basic("ana:texto")).await).await,
+            Some("ana")
+        );
+        assert_eq!(
+            authenticate(&config, &handshake(&basic("caio:forte")).await).await,
+            Some("caio")
+        );
+        assert_eq!(
+            authenticate(&config, &handshake(&basic("caio:fraco")).await).await,
+            None
+        );
+        // Mesmo com a senha de outro usuário, um nome inexistente é recusado.
+        assert_eq!(
+            authenticate(&config, &handshake(&basic("davi:forte")).await).await,
+            None
+        );
+        // Token não vale quando só "basic" está habilitado.
+        let header = handshake("GET / HTTP/1.1\r\nX-Auth-Token: texto\r\n\r\n").await;
+        assert_eq!(authenticate(&config, &header).await, None);
+    }
+
+    #[test]
+    fn rejection_output() {
+        let config = auth(
+            "realm = \"Teste\"\n[[users]]\nname = \"ana\"\npassword = \"x\"",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            rejection(&config).unwrap(),
+            format!(
+                "HTTP/1.1 {}\r\nProxy-Authenticate: Basic realm=\"Teste\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                config.reject_status
+            )
+        );
+
+        let config = auth(
+            "methods = [\"header\"]\nreject_status = \"403 Forbidden\"\n[[users]]\nname = \"ana\"\npassword = \"x\"",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            rejection(&config).unwrap(),
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+
+        let config = auth(
+            "reject = \"close\"\n[[users]]\nname = \"ana\"\npassword = \"x\"",
+            None,
+        )
+        .unwrap();
+        assert!(rejection(&config).is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config::{BandwidthConfig, Config, RateConfig};
+use crate::limits::limit_key;
+use crate::session::Direction;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use tokio::time::{self, Duration, Instant};
+
+// Sem burst_kib, o balde comporta um segundo de tráfego, com um mínimo para não
+// fragmentar demais as leituras em taxas baixas.
+const MIN_DEFAULT_BURST: f64 = 4096.0;
+
+// Baldes de taxa compartilhados: um por listener (total), um por IP em cada listener
+// e um por usuário autenticado.
+pub struct Bandwidth {
+    listeners: Vec<BandwidthConfig>,
+    totals: Vec<Option<Arc<RatePair>>>,
+    per_ip: Mutex<HashMap<(usize, IpAddr), Weak<RatePair>>>,
+    per_user: Mutex<HashMap<String, Weak<RatePair>>>,
+}
+
+pub struct RatePair {
+    up: Option<Bucket>,
+    down: Option<Bucket>,
+}
+
+// Token bucket em bytes. O saldo pode ficar negativo: quem retira além do disponível
+// espera o tempo de repor a dívida, o que mantém a taxa mesmo com várias sessões no balde.
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+// Baldes que se aplicam a uma sessão; cada bloco repassado passa por todos eles.
+pub struct Shaper {
+    pairs: Vec<Arc<RatePair>>,
+    ip_key: Option<(usize, IpAddr)>,
+    // Definido depois do handshake, quando o usuário tem limite próprio.
+    user: OnceLock<(String, Arc<RatePair>)>,
+    bandwidth: Arc<Bandwidth>,
+}
+
+impl Bandwidth {
+    pub fn new(config: &Config) -> Self {
+        let listeners: Vec<BandwidthConfig> = config
+            .listeners
+            .iter()
+            .map(|listener| config.bandwidth_for(listener))
+            .collect();
+        let totals = listeners
+            .iter()
+            .map(|bandwidth| {
+                bandwidth
+                    .total
+                    .as_ref()
+                    .map(|rate| Arc::new(RatePair::new(rate)))
+            })
+            .collect();
+        Bandwidth {
+            listeners,
+            totals,
+            per_ip: Mutex::new(HashMap::new()),
+            per_user: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn shaper(self: &Arc<Self>, listener_index: usize, ip: IpAddr) -> Shaper {
+        let config = &self.listeners[listener_index];
+        let mut pairs = Vec::new();
+        if let Some(rate) = &config.per_session {
+            pairs.push(Arc::new(RatePair::new(rate)));
+        }
+        let mut ip_key = None;
+        if let Some(rate) = &config.per_ip {
+            let key = (listener_index, limit_key(ip));
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let pair = match per_ip.get(&key).and_then(Weak::upgrade) {
+                Some(pair) => pair,
+                None => {
+                    let pair = Arc::new(RatePair::new(rate));
+                    per_ip.insert(key, Arc::downgrade(&pair));
+                    pair
+                }
+            };
+            pairs.push(pair);
+            ip_key = Some(key);
+        }
+        if let Some(pair) = &self.totals[listener_index] {
+            pairs.push(pair.clone());
+        }
+        Shaper {
+            pairs,
+            ip_key,
+            user: OnceLock::new(),
+            bandwidth: self.clone(),
+        }
+    }
+}
+
+impl RatePair {
+    pub fn new(config: &RateConfig) -> Self {
+        let bucket = |kbit: Option<u64>| kbit.map(|kbit| Bucket::new(kbit, config.burst_kib));
+        RatePair {
+            up: bucket(config.up_kbit),
+            down: bucket(config.down_kbit),
+        }
+    }
+
+    fn get(&self, direction: Direction) -> Option<&Bucket> {
+        match direction {
+            Direction::Up => self.up.as_ref(),
+            Direction::Down => self.down.as_ref(),
+        }
+    }
+}
+
+impl Bucket {
+    fn new(kbit: u64, burst_kib: Option<u64>) -> Self {
+        let rate = kbit as f64 * 1000.0 / 8.0;
+        let burst = match burst_kib {
+            Some(kib) => kib as f64 * 1024.0,
+            None => rate.max(MIN_DEFAULT_BURST),
+        };
+        Bucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    // Retira `n` bytes e devolve quanto esperar até o saldo voltar a zero.
+    fn reserve(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, updated) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*updated).as_secs_f64() * self.rate).min(self.burst);
+        *updated = now;
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+impl Shaper {
+    pub fn set_user(&self, name: &str, rate: &RateConfig) {
+        let mut per_user = self.bandwidth.per_user.lock().unwrap();
+        let pair = match per_user.get(name).and_then(Weak::upgrade) {
+            Some(pair) => pair,
+            None => {
+                let pair = Arc::new(RatePair::new(rate));
+                per_user.insert(name.to_string(), Arc::downgrade(&pair));
+                pair
+            }
+        };
+        let _ = self.user.set((name.to_string(), pair));
+    }
+
+    // Tamanho máximo de uma leitura: nunca mais que o menor burst, para que a espera
+    // seja distribuída em blocos em vez de uma rajada seguida de uma pausa longa.
+    pub fn chunk_len(&self, direction: Direction, len: usize) -> usize {
+        self.buckets(direction)
+            .map(|bucket| bucket.burst as usize)
+            .fold(len, usize::min)
+            .max(1)
+    }
+
+    // Contabiliza `n` bytes em todos os baldes e espera o maior atraso entre eles.
+    // Retorna se houve espera.
+    pub async fn throttle(&self, direction: Direction, n: usize) -> bool {
+        let delay = self
+            .buckets(direction)
+            .map(|bucket| bucket.reserve(n))
+            .max()
+            .unwrap_or_default();
+        if delay.is_zero() {
+            return false;
+        }
+        time::sleep(delay).await;
+        true
+    }
+
+    fn buckets(&self, direction: Direction) -> impl Iterator<Item = &Bucket> {
+        self.pairs
+            .iter()
+            .chain(self.user.get().map(|(_, pair)| pair))
+            .filter_map(move |pair| pair.get(direction))
+    }
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        // Os upgrades em shaper() e set_user() acontecem sob os mesmos locks, então não
+        // há corrida aqui.
+        if let Some(key) = self.ip_key {
+            let mut per_ip = self.bandwidth.per_ip.lock().unwrap();
+            self.pairs.clear();
+            if per_ip
+                .get(&key)
+                .is_some_and(|pair| pair.strong_count() == 0)
+            {
+                per_ip.remove(&key);
+            }
+        }
+        if let Some((name, pair)) = self.user.take() {
+            let mut per_user = self.bandwidth.per_user.lock().unwrap();
+            drop(pair);
+            if per_user
+                .get(&name)
+                .is_some_and(|pair| pair.strong_count() == 0)
+            {
+                per_user.remove(&name);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::session::Session;
+use std::io::Error;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout, Duration};
+
+// Classes de tamanho em potências de dois, de 1 KiB até o máximo de buffer_size (1 MiB).
+const MIN_CLASS_SHIFT: u32 = 10;
+const CLASSES: usize = 11;
+// Tamanho inicial do buffer de cada sentido; sessões ociosas ficam nele.
+const START_SIZE: usize = 2048;
+// Leituras pequenas seguidas antes de voltar para a classe menor.
+const SHRINK_AFTER: u32 = 8;
+// Leitura sem dados por este tempo faz o buffer voltar ao tamanho inicial enquanto espera.
+pub const IDLE_AFTER: Duration = Duration::from_secs(1);
+// Quanto o pool guarda de buffers devolvidos; o excedente volta para o alocador.
+const POOL_MAX_IDLE_BYTES: i64 = 16 * 1024 * 1024;
+
+static POOL: Pool = Pool {
+    classes: [const { Mutex::new(Vec::new()) }; CLASSES],
+    idle_bytes: AtomicI64::new(0),
+};
+
+struct Pool {
+    classes: [Mutex<Vec<Vec<u8>>>; CLASSES],
+    idle_bytes: AtomicI64,
+}
+
+// Buffer de leitura de um sentido do relay. Começa pequeno, dobra sempre que uma leitura
+// o enche (até `max`) e encolhe depois de uma sequência de leituras que usam menos de um
+// quarto dele ou depois de IDLE_AFTER sem dados. O tamanho em uso é contabilizado na sessão.
+pub struct AdaptiveBuffer<'a> {
+    buffer: Vec<u8>,
+    max: usize,
+    small_reads: u32,
+    session: &'a Session,
+}
+
+impl<'a> AdaptiveBuffer<'a> {
+    pub fn new(max: usize, session: &'a Session) -> Self {
+        let buffer = take(START_SIZE.min(max));
+        session.charge_buffer(buffer.len() as i64);
+        AdaptiveBuffer {
+            buffer,
+            max,
+            small_reads: 0,
+            session,
+        }
+    }
+
+    pub fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    // Lê do stream até `chunk_len(tamanho do buffer)` bytes. Um túnel que fica parado depois
+    // de uma rajada não faz leituras pequenas, então o buffer encolhe pelo tempo de espera;
+    // cancelar a leitura pendente não perde dados.
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+        chunk_len: impl Fn(usize) -> usize,
+    ) -> Result<usize, Error> {
+        let len = chunk_len(self.buffer.len());
+        if let Ok(result) = timeout(IDLE_AFTER, stream.read(&mut self.buffer[..len])).await {
+            return result;
+        }
+        self.release_idle();
+        let len = chunk_len(self.buffer.len());
+        stream.read(&mut self.buffer[..len]).await
+    }
+
+    // Volta ao tamanho inicial enquanto não há dados para ler.
+    pub fn release_idle(&mut self) {
+        self.small_reads = 0;
+        let start = START_SIZE.min(self.max);
+        if self.buffer.len() > start {
+            self.resize(start);
+        }
+    }
+
+    // Ajusta o tamanho para a próxima leitura a partir de quanto a última trouxe.
+    pub fn record(&mut self, bytes_read: usize) {
+        let len = self.buffer.len();
+        if bytes_read == len && len < self.max {
+            self.small_reads = 0;
+            self.resize((len * 2).min(self.max));
+        } else if bytes_read <= len / 4 && len > START_SIZE.min(self.max) {
+            self.small_reads += 1;
+            if self.small_reads >= SHRINK_AFTER {
+                self.small_reads = 0;
+                self.resize(len / 2);
+            }
+        } else {
+            self.small_reads = 0;
+        }
+    }
+
+    fn resize(&mut self, len: usize) {
+        let old = std::mem::replace(&mut self.buffer, take(len));
+        self.session
+            .charge_buffer(self.buffer.len() as i64 - old.len() as i64);
+        give_back(old);
+    }
+}
+
+impl Drop for AdaptiveBuffer<'_> {
+    fn drop(&mut self) {
+        self.session.charge_buffer(-(self.buffer.len() as i64));
+        give_back(std::mem::take(&mut self.buffer));
+    }
+}
+
+// Bytes em buffers livres guardados no pool.
+pub fn idle_bytes() -> i64 {
+    POOL.idle_bytes.load(Ordering::Relaxed)
+}
+
+// Arredonda para a classe de cima; buffer_size fora de potência de dois usa a classe maior.
+fn class_of(len: usize) -> usize {
+    let shift = len.next_power_of_two().trailing_zeros();
+    (shift.saturating_sub(MIN_CLASS_SHIFT) as usize).min(CLASSES - 1)
+}
+
+fn take(len: usize) -> Vec<u8> {
+    let recycled = POOL.classes[class_of(len)].lock().unwrap().pop();
+    match recycled {
+        Some(mut buffer) => {
+            POOL.idle_bytes
+                .fetch_sub(buffer.capacity() as i64, Ordering::Relaxed);
+            buffer.resize(len, 0);
+            buffer
+        }
+        None => {
+            let mut buffer = Vec::with_capacity(class_capacity(len));
+            buffer.resize(len, 0);
+            buffer
+        }
+    }
+}
+
+fn give_back(buffer: Vec<u8>) {
+    let capacity = buffer.capacity() as i64;
+    if capacity == 0 || idle_bytes() + capacity > POOL_MAX_IDLE_BYTES {
+        return;
+    }
+    POOL.idle_bytes.fetch_add(capacity, Ordering::Relaxed);
+    POOL.classes[class_of(buffer.capacity())]
+        .lock()
+        .unwrap()
+        .push(buffer);
+}
+
+// Todos os buffers de uma classe têm a mesma capacidade, então qualquer um serve
+// para qualquer tamanho da classe sem realocar.
+fn class_capacity(len: usize) -> usize {
+    len.next_power_of_two().max(1 << MIN_CLASS_SHIFT)
+}
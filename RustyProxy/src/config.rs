@@ -0,0 +1,960 @@
+use crate::auth::{self, Credential};
+use crate::routing::{Cidr, HexBytes, Pattern};
+use crate::sniff::Protocol;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PORT: u16 = 80;
+const DEFAULT_STATUS: &str = "@RustyManager";
+const DEFAULT_BUFFER_SIZE: usize = 32768;
+const DEFAULT_PEEK_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_LINGER_SECS: u64 = 10;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_SSH_KEYWORD: &str = "SSH";
+const DEFAULT_SSH_TARGET_ADDR: &str = "127.0.0.1:22";
+const DEFAULT_OPENVPN_TARGET_ADDR: &str = "127.0.0.1:1194";
+
+const MIN_BUFFER_SIZE: usize = 1024;
+const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+const MAX_STATUS_LEN: usize = 128;
+const DEFAULT_REJECT_STATUS: &str = "429 Too Many Requests";
+const DEFAULT_AUTH_REJECT_STATUS: &str = "407 Proxy Authentication Required";
+const DEFAULT_AUTH_HEADER: &str = "X-Auth-Token";
+const DEFAULT_AUTH_REALM: &str = "RustyProxy";
+const DEFAULT_QUOTA_STORE: &str = "/etc/rustyproxy/usage.json";
+const DEFAULT_QUOTA_FLUSH_SECS: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    // No Linux, repassa TCP puro com splice(2) em vez de copiar por buffer_size.
+    #[serde(default)]
+    pub splice: bool,
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    // Valores padrão para todos os listeners; cada listener pode sobrescrever campo a campo.
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    pub quota: Option<QuotaConfig>,
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub log: LogConfig,
+    pub access_log: Option<AccessLogConfig>,
+    pub backends: BTreeMap<String, String>,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    // Sem default_backend, conexões que não casam com nenhuma regra são encerradas.
+    pub default_backend: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub port: u16,
+    #[serde(default = "default_status")]
+    pub status: String,
+    // Após o 101, decodifica/codifica frames WebSocket em vez de repassar bytes crus.
+    #[serde(default)]
+    pub websocket_frames: bool,
+    pub tls: Option<TlsConfig>,
+    // Quando ausentes, valem as regras e o default_backend globais.
+    pub rules: Option<Vec<RuleConfig>>,
+    pub default_backend: Option<String>,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    // Com [auth] definido, a autenticação vale para todos os listeners, exceto os com auth = false.
+    pub auth: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    // Certificado padrão, usado quando o SNI não tem certificado próprio em cert_dir.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub cert_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    #[serde(default = "default_peek_timeout_ms")]
+    pub peek_ms: u64,
+    // Prazo, desde o accept, para concluir TLS, handshake, espiada e connect no backend.
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_ms: u64,
+    // Encerra túneis sem tráfego em nenhum sentido por esse tempo.
+    pub idle_secs: Option<u64>,
+    // Duração máxima de uma sessão, com ou sem tráfego.
+    pub max_session_secs: Option<u64>,
+    // Depois que um lado encerra, quanto esperar pelo fim do outro sentido.
+    #[serde(default = "default_linger_secs")]
+    pub linger_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Conexões simultâneas no processo todo; ao atingir, o accept fica pausado.
+    pub max_connections: Option<usize>,
+    // Sessões simultâneas por IP de origem (por /64 no IPv6).
+    pub per_ip: Option<usize>,
+    #[serde(default)]
+    pub reject: RejectMode,
+    #[serde(default = "default_reject_status")]
+    pub reject_status: String,
+}
+
+// Limites de banda de cada sessão, de cada IP (por /64 no IPv6) e do listener inteiro.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthConfig {
+    pub per_session: Option<RateConfig>,
+    pub per_ip: Option<RateConfig>,
+    pub total: Option<RateConfig>,
+}
+
+// Taxas em kbit/s (1 kbit = 1000 bits) e rajada em KiB; sem burst_kib, um segundo de taxa.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    pub up_kbit: Option<u64>,
+    pub down_kbit: Option<u64>,
+    pub burst_kib: Option<u64>,
+}
+
+// Autenticação no handshake HTTP, antes da resposta 101/200.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default = "default_auth_methods")]
+    pub methods: Vec<AuthMethod>,
+    // Cabeçalho lido pelo método "header".
+    #[serde(default = "default_auth_header")]
+    pub header: String,
+    #[serde(default = "default_auth_realm")]
+    pub realm: String,
+    // Arquivo no formato do htpasswd (usuário:senha), com senhas bcrypt, {SHA} ou em texto.
+    pub htpasswd: Option<PathBuf>,
+    #[serde(default = "default_auth_reject")]
+    pub reject: RejectMode,
+    #[serde(default = "default_auth_reject_status")]
+    pub reject_status: String,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    // Usuários de `users` e do htpasswd, carregados em Config::from_file.
+    #[serde(skip)]
+    pub credentials: Vec<Credential>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    // Proxy-Authorization: Basic
+    Basic,
+    // Token no cabeçalho definido em auth.header.
+    Header,
+    // Token como primeiro segmento do caminho (GET /<token>/ ...).
+    Path,
+}
+
+// Sem password, o usuário precisa existir no htpasswd; a entrada aqui só acrescenta limites.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: Option<String>,
+    pub quota_gib: Option<f64>,
+    pub bandwidth: Option<RateConfig>,
+}
+
+// Cota de tráfego (enviado + recebido) por IP, zerada a cada período.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    // Cota padrão de cada IP, em GiB; sem ela, só os IPs de `ips` são limitados.
+    pub limit_gib: Option<f64>,
+    #[serde(default)]
+    pub period: QuotaPeriod,
+    // Dia do mês (1 a 28) ou da semana (1 = segunda a 7 = domingo) em que o período recomeça.
+    #[serde(default = "default_reset_day")]
+    pub reset_day: u32,
+    #[serde(default = "default_quota_store")]
+    pub store: PathBuf,
+    #[serde(default = "default_quota_flush_secs")]
+    pub flush_secs: u64,
+    // Cotas específicas por IP, em GiB.
+    #[serde(default)]
+    pub ips: BTreeMap<IpAddr, f64>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Weekly,
+    #[default]
+    Monthly,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RejectMode {
+    #[default]
+    Close,
+    Http,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+// Sem path, o access log vai para a saída padrão.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// Uma regra casa quando todas as condições informadas são verdadeiras.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub backend: String,
+    pub protocol: Option<Protocol>,
+    pub contains: Option<String>,
+    pub prefix: Option<String>,
+    pub prefix_hex: Option<HexBytes>,
+    pub regex: Option<Pattern>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub sni: Option<String>,
+    pub alpn: Option<String>,
+    pub source: Option<Cidr>,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub empty: bool,
+    #[serde(default)]
+    pub forward_http: bool,
+}
+
+impl RuleConfig {
+    fn new(backend: &str) -> Self {
+        RuleConfig {
+            backend: backend.to_string(),
+            protocol: None,
+            contains: None,
+            prefix: None,
+            prefix_hex: None,
+            regex: None,
+            host: None,
+            path: None,
+            sni: None,
+            alpn: None,
+            source: None,
+            port: None,
+            empty: false,
+            forward_http: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: None,
+            per_ip: None,
+            reject: RejectMode::default(),
+            reject_status: default_reject_status(),
+        }
+    }
+}
+
+impl QuotaPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "diário",
+            QuotaPeriod::Weekly => "semanal",
+            QuotaPeriod::Monthly => "mensal",
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: default_log_level(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        TimeoutsConfig {
+            peek_ms: DEFAULT_PEEK_TIMEOUT_MS,
+            handshake_ms: DEFAULT_HANDSHAKE_TIMEOUT_MS,
+            idle_secs: None,
+            max_session_secs: None,
+            linger_secs: DEFAULT_LINGER_SECS,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config, Error> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("não foi possível ler {}: {}", path.display(), e),
+            )
+        })?;
+        let mut config: Config = toml::from_str(&content)
+            .map_err(|e| invalid(format!("{} inválido: {}", path.display(), e)))?;
+        if let Some(auth) = &mut config.auth {
+            auth.credentials = auth::load_credentials(auth)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Configuração equivalente ao comportamento original com --port/--status.
+    pub fn from_args(listeners: Vec<(u16, String)>) -> Result<Config, Error> {
+        let mut backends = BTreeMap::new();
+        backends.insert("ssh".to_string(), DEFAULT_SSH_TARGET_ADDR.to_string());
+        backends.insert(
+            "openvpn".to_string(),
+            DEFAULT_OPENVPN_TARGET_ADDR.to_string(),
+        );
+
+        let config = Config {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            splice: false,
+            listeners: listeners
+                .into_iter()
+                .map(|(port, status)| ListenerConfig {
+                    port,
+                    status,
+                    websocket_frames: false,
+                    tls: None,
+                    rules: None,
+                    default_backend: None,
+                    bandwidth: BandwidthConfig::default(),
+                    auth: None,
+                })
+                .collect(),
+            timeouts: TimeoutsConfig::default(),
+            limits: LimitsConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            quota: None,
+            auth: None,
+            log: LogConfig::default(),
+            access_log: None,
+            backends,
+            rules: vec![
+                RuleConfig {
+                    contains: Some(DEFAULT_SSH_KEYWORD.to_string()),
+                    ..RuleConfig::new("ssh")
+                },
+                RuleConfig {
+                    empty: true,
+                    ..RuleConfig::new("ssh")
+                },
+                RuleConfig {
+                    protocol: Some(Protocol::OpenVpn),
+                    ..RuleConfig::new("openvpn")
+                },
+            ],
+            default_backend: None,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn backend_addr(&self, name: &str) -> &str {
+        &self.backends[name]
+    }
+
+    pub fn rules_for<'a>(&'a self, listener: &'a ListenerConfig) -> &'a [RuleConfig] {
+        listener.rules.as_deref().unwrap_or(&self.rules)
+    }
+
+    pub fn default_backend_for<'a>(&'a self, listener: &'a ListenerConfig) -> Option<&'a str> {
+        listener
+            .default_backend
+            .as_deref()
+            .or(self.default_backend.as_deref())
+    }
+
+    pub fn bandwidth_for(&self, listener: &ListenerConfig) -> BandwidthConfig {
+        BandwidthConfig {
+            per_session: listener
+                .bandwidth
+                .per_session
+                .or(self.bandwidth.per_session),
+            per_ip: listener.bandwidth.per_ip.or(self.bandwidth.per_ip),
+            total: listener.bandwidth.total.or(self.bandwidth.total),
+        }
+    }
+
+    pub fn auth_for(&self, listener: &ListenerConfig) -> Option<&AuthConfig> {
+        match listener.auth {
+            Some(false) => None,
+            _ => self.auth.as_ref(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&self.buffer_size) {
+            return Err(invalid(format!(
+                "buffer_size deve estar entre {} e {}",
+                MIN_BUFFER_SIZE, MAX_BUFFER_SIZE
+            )));
+        }
+
+        if self.timeouts.peek_ms == 0 {
+            return Err(invalid("timeouts.peek_ms deve ser maior que zero"));
+        }
+        if self.timeouts.handshake_ms <= self.timeouts.peek_ms {
+            return Err(invalid(
+                "timeouts.handshake_ms deve ser maior que timeouts.peek_ms",
+            ));
+        }
+        if self.timeouts.idle_secs == Some(0) {
+            return Err(invalid("timeouts.idle_secs deve ser maior que zero"));
+        }
+        if self.timeouts.max_session_secs == Some(0) {
+            return Err(invalid("timeouts.max_session_secs deve ser maior que zero"));
+        }
+
+        if self.limits.max_connections == Some(0) {
+            return Err(invalid("limits.max_connections deve ser maior que zero"));
+        }
+        if self.limits.per_ip == Some(0) {
+            return Err(invalid("limits.per_ip deve ser maior que zero"));
+        }
+        validate_status("limits.reject_status", &self.limits.reject_status)?;
+
+        validate_bandwidth("bandwidth", &self.bandwidth)?;
+        if let Some(auth) = &self.auth {
+            self.validate_auth(auth)?;
+        }
+        if let Some(quota) = &self.quota {
+            let user_quotas = self
+                .auth
+                .iter()
+                .flat_map(|auth| &auth.users)
+                .any(|user| user.quota_gib.is_some());
+            validate_quota(quota, user_quotas)?;
+        }
+
+        if self.backends.is_empty() {
+            return Err(invalid("nenhum backend definido em [backends]"));
+        }
+        for (name, addr) in &self.backends {
+            validate_addr(addr).map_err(|e| invalid(format!("backend '{}': {}", name, e)))?;
+        }
+
+        if let Some(name) = &self.default_backend {
+            self.validate_backend_ref("default_backend", name)?;
+        }
+        self.validate_rules("rules", &self.rules)?;
+
+        if self.listeners.is_empty() {
+            return Err(invalid("nenhum listener definido em [[listeners]]"));
+        }
+        let mut ports = BTreeSet::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let prefix = format!("listeners[{}]", i);
+            if !ports.insert(listener.port) {
+                return Err(invalid(format!(
+                    "{}: porta {} repetida",
+                    prefix, listener.port
+                )));
+            }
+            self.validate_listener(&prefix, listener)?;
+        }
+
+        Ok(())
+    }
+
+    fn validate_listener(&self, prefix: &str, listener: &ListenerConfig) -> Result<(), Error> {
+        if listener.port == 0 {
+            return Err(invalid(format!(
+                "{}.port deve estar entre 1 e 65535",
+                prefix
+            )));
+        }
+        if listener.status.is_empty() || listener.status.len() > MAX_STATUS_LEN {
+            return Err(invalid(format!(
+                "{}.status deve ter entre 1 e {} caracteres",
+                prefix, MAX_STATUS_LEN
+            )));
+        }
+        if listener.status.contains(['\r', '\n']) {
+            return Err(invalid(format!(
+                "{}.status não pode conter quebras de linha",
+                prefix
+            )));
+        }
+        if let Some(name) = &listener.default_backend {
+            self.validate_backend_ref(&format!("{}.default_backend", prefix), name)?;
+        }
+        if let Some(rules) = &listener.rules {
+            self.validate_rules(&format!("{}.rules", prefix), rules)?;
+        }
+        validate_bandwidth(&format!("{}.bandwidth", prefix), &listener.bandwidth)?;
+        if listener.auth == Some(true) && self.auth.is_none() {
+            return Err(invalid(format!(
+                "{}.auth: a configuração não tem a seção [auth]",
+                prefix
+            )));
+        }
+        Ok(())
+    }
+
+    fn validate_auth(&self, auth: &AuthConfig) -> Result<(), Error> {
+        if auth.methods.is_empty() {
+            return Err(invalid("auth.methods: informe ao menos um método"));
+        }
+        if auth.header.is_empty() || auth.header.contains([':', ' ', '\r', '\n']) {
+            return Err(invalid("auth.header deve ser um nome de cabeçalho válido"));
+        }
+        if auth.realm.contains(['"', '\r', '\n']) {
+            return Err(invalid(
+                "auth.realm não pode conter aspas nem quebras de linha",
+            ));
+        }
+        validate_status("auth.reject_status", &auth.reject_status)?;
+        for (i, user) in auth.users.iter().enumerate() {
+            let field = format!("auth.users[{}]", i);
+            if let Some(gib) = user.quota_gib {
+                if self.quota.is_none() {
+                    return Err(invalid(format!(
+                        "{}.quota_gib: a configuração não tem a seção [quota]",
+                        field
+                    )));
+                }
+                if !gib.is_finite() || gib < 0.0 {
+                    return Err(invalid(format!(
+                        "{}.quota_gib deve ser um número de GiB não negativo",
+                        field
+                    )));
+                }
+            }
+            if let Some(rate) = &user.bandwidth {
+                validate_rate(&format!("{}.bandwidth", field), rate)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_rules(&self, prefix: &str, rules: &[RuleConfig]) -> Result<(), Error> {
+        for (i, rule) in rules.iter().enumerate() {
+            let field = format!("{}[{}]", prefix, i);
+            self.validate_backend_ref(&field, &rule.backend)?;
+            if !rule.has_condition() {
+                return Err(invalid(format!(
+                    "{}: a regra precisa de ao menos uma condição",
+                    field
+                )));
+            }
+            for (name, value) in [
+                ("contains", &rule.contains),
+                ("prefix", &rule.prefix),
+                ("host", &rule.host),
+                ("path", &rule.path),
+                ("sni", &rule.sni),
+                ("alpn", &rule.alpn),
+            ] {
+                if value.as_deref() == Some("") {
+                    return Err(invalid(format!("{}: {} não pode ser vazio", field, name)));
+                }
+            }
+            if rule.forward_http && rule.empty {
+                return Err(invalid(format!(
+                    "{}: forward_http não pode ser combinado com empty",
+                    field
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_backend_ref(&self, field: &str, name: &str) -> Result<(), Error> {
+        if !self.backends.contains_key(name) {
+            return Err(invalid(format!(
+                "{}: backend '{}' não existe em [backends]",
+                field, name
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn validate_bandwidth(prefix: &str, bandwidth: &BandwidthConfig) -> Result<(), Error> {
+    for (name, rate) in [
+        ("per_session", &bandwidth.per_session),
+        ("per_ip", &bandwidth.per_ip),
+        ("total", &bandwidth.total),
+    ] {
+        if let Some(rate) = rate {
+            validate_rate(&format!("{}.{}", prefix, name), rate)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_rate(field: &str, rate: &RateConfig) -> Result<(), Error> {
+    if rate.up_kbit.is_none() && rate.down_kbit.is_none() {
+        return Err(invalid(format!(
+            "{}: informe up_kbit, down_kbit ou ambos",
+            field
+        )));
+    }
+    for (key, value) in [
+        ("up_kbit", rate.up_kbit),
+        ("down_kbit", rate.down_kbit),
+        ("burst_kib", rate.burst_kib),
+    ] {
+        if value == Some(0) {
+            return Err(invalid(format!(
+                "{}.{} deve ser maior que zero",
+                field, key
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_status(field: &str, status: &str) -> Result<(), Error> {
+    if status.is_empty() || status.len() > MAX_STATUS_LEN || status.contains(['\r', '\n']) {
+        return Err(invalid(format!(
+            "{} deve ter entre 1 e {} caracteres, sem quebras de linha",
+            field, MAX_STATUS_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn validate_quota(quota: &QuotaConfig, user_quotas: bool) -> Result<(), Error> {
+    if quota.limit_gib.is_none() && quota.ips.is_empty() && !user_quotas {
+        return Err(invalid(
+            "quota: informe limit_gib, ips ou quota_gib em algum usuário de [auth]",
+        ));
+    }
+    let days = match quota.period {
+        QuotaPeriod::Daily => 1..=1,
+        QuotaPeriod::Weekly => 1..=7,
+        QuotaPeriod::Monthly => 1..=28,
+    };
+    if !days.contains(&quota.reset_day) {
+        return Err(invalid(format!(
+            "quota.reset_day deve estar entre {} e {} para o período {}",
+            days.start(),
+            days.end(),
+            quota.period.as_str()
+        )));
+    }
+    for gib in quota.limit_gib.iter().chain(quota.ips.values()) {
+        if !gib.is_finite() || *gib < 0.0 {
+            return Err(invalid(
+                "quota: as cotas devem ser números de GiB não negativos",
+            ));
+        }
+    }
+    if quota.flush_secs == 0 {
+        return Err(invalid("quota.flush_secs deve ser maior que zero"));
+    }
+    Ok(())
+}
+
+fn validate_addr(addr: &str) -> Result<(), String> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| format!("endereço '{}' deve estar no formato host:porta", addr))?;
+    if host.is_empty() {
+        return Err(format!("endereço '{}' sem host", addr));
+    }
+    match port.parse::<u16>() {
+        Ok(p) if p > 0 => Ok(()),
+        _ => Err(format!("porta inválida em '{}'", addr)),
+    }
+}
+
+pub fn invalid(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+pub fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+pub fn default_status() -> String {
+    DEFAULT_STATUS.to_string()
+}
+
+fn default_buffer_size() -> usize {
+    DEFAULT_BUFFER_SIZE
+}
+
+fn default_peek_timeout_ms() -> u64 {
+    DEFAULT_PEEK_TIMEOUT_MS
+}
+
+fn default_reject_status() -> String {
+    DEFAULT_REJECT_STATUS.to_string()
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    DEFAULT_HANDSHAKE_TIMEOUT_MS
+}
+
+fn default_linger_secs() -> u64 {
+    DEFAULT_LINGER_SECS
+}
+
+fn default_auth_methods() -> Vec<AuthMethod> {
+    vec![AuthMethod::Basic]
+}
+
+fn default_auth_header() -> String {
+    DEFAULT_AUTH_HEADER.to_string()
+}
+
+fn default_auth_realm() -> String {
+    DEFAULT_AUTH_REALM.to_string()
+}
+
+fn default_auth_reject() -> RejectMode {
+    RejectMode::Http
+}
+
+fn default_auth_reject_status() -> String {
+    DEFAULT_AUTH_REJECT_STATUS.to_string()
+}
+
+fn default_reset_day() -> u32 {
+    1
+}
+
+fn default_quota_store() -> PathBuf {
+    PathBuf::from(DEFAULT_QUOTA_STORE)
+}
+
+fn default_quota_flush_secs() -> u64 {
+    DEFAULT_QUOTA_FLUSH_SECS
+}
+
+fn default_log_level() -> String {
+    DEFAULT_LOG_LEVEL.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "[backends]\nssh = \"127.0.0.1:22\"\n";
+
+    // `top` vem antes das tabelas, `tables` depois de [[listeners]] e [backends].
+    fn validate(top: &str, listener: &str, tables: &str) -> Result<(), String> {
+        let config: Config = toml::from_str(&format!(
+            "{}\n[[listeners]]\nport = 80\n{}\n{}{}",
+            top, listener, BASE, tables
+        ))
+        .map_err(|e| e.to_string())?;
+        config.validate().map_err(|e| e.to_string())
+    }
+
+    fn error(top: &str, listener: &str, tables: &str) -> String {
+        validate(top, listener, tables).err().unwrap()
+    }
+
+    #[test]
+    fn minimal_and_default_configs_are_valid() {
+        assert_eq!(validate("", "", ""), Ok(()));
+        assert!(Config::from_args(vec![(80, default_status())]).is_ok());
+        assert!(Config::from_args(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn buffer_and_timeouts() {
+        assert!(error("buffer_size = 512", "", "").contains("buffer_size"));
+        assert!(error("buffer_size = 2097152", "", "").contains("buffer_size"));
+        assert_eq!(validate("buffer_size = 1024", "", ""), Ok(()));
+        assert!(error("", "", "[timeouts]\npeek_ms = 0").contains("peek_ms"));
+        assert!(
+            error("", "", "[timeouts]\npeek_ms = 5000\nhandshake_ms = 5000")
+                .contains("handshake_ms deve ser maior")
+        );
+        assert!(error("", "", "[timeouts]\nidle_secs = 0").contains("idle_secs"));
+        assert!(error("", "", "[timeouts]\nmax_session_secs = 0").contains("max_session_secs"));
+    }
+
+    #[test]
+    fn listeners() {
+        let config = |listeners: &str| {
+            toml::from_str::<Config>(&format!("{}\n{}", listeners, BASE))
+                .unwrap()
+                .validate()
+                .map_err(|e| e.to_string())
+        };
+        assert!(config("listeners = []")
+            .unwrap_err()
+            .contains("nenhum listener"));
+        assert!(config("[[listeners]]\nport = 80\n[[listeners]]\nport = 80")
+            .unwrap_err()
+            .contains("listeners[1]: porta 80 repetida"));
+        assert!(config("[[listeners]]\nport = 0")
+            .unwrap_err()
+            .contains("listeners[0].port"));
+        assert!(error("", "status = \"\"", "").contains("listeners[0].status"));
+        assert!(error("", "status = \"a\\r\\nX: y\"", "").contains("quebras de linha"));
+        assert!(error(
+            "",
+            &format!("status = \"{}\"", "a".repeat(MAX_STATUS_LEN + 1)),
+            ""
+        )
+        .contains("listeners[0].status"));
+        assert!(error("", "auth = true", "").contains("listeners[0].auth"));
+        assert!(error("", "default_backend = \"web\"", "").contains("backend 'web' não existe"));
+    }
+
+    #[test]
+    fn backends() {
+        let config = |backends: &str| {
+            toml::from_str::<Config>(&format!(
+                "[[listeners]]\nport = 80\n[backends]\n{}",
+                backends
+            ))
+            .unwrap()
+            .validate()
+            .map_err(|e| e.to_string())
+        };
+        assert!(config("").unwrap_err().contains("nenhum backend"));
+        assert!(config("ssh = \"127.0.0.1\"")
+            .unwrap_err()
+            .contains("host:porta"));
+        assert!(config("ssh = \":22\"").unwrap_err().contains("sem host"));
+        assert!(config("ssh = \"127.0.0.1:0\"")
+            .unwrap_err()
+            .contains("porta inválida"));
+        assert!(config("ssh = \"[::1]:22\"").is_ok());
+        assert!(config("ssh = \"localhost:22\"").is_ok());
+    }
+
+    #[test]
+    fn rules() {
+        let rule = |body: &str| format!("[[rules]]\nbackend = \"ssh\"\n{}", body);
+        assert_eq!(validate("", "", &rule("contains = \"SSH\"")), Ok(()));
+        assert!(error("", "", &rule("")).contains("rules[0]: a regra precisa"));
+        assert!(error("", "", &rule("contains = \"\"")).contains("contains não pode ser vazio"));
+        assert!(error("", "", &rule("empty = true\nforward_http = true")).contains("forward_http"));
+        assert!(error("", "", "[[rules]]\nbackend = \"web\"\nempty = true")
+            .contains("'web' não existe"));
+        assert!(error("", "", &rule("source = \"10.0.0.0/40\"")).contains("prefixo inválido"));
+        assert!(error("", "", &rule("regex = \"(\"")).contains("regex inválida"));
+        assert!(error("", "", &rule("protocolo = \"ssh\"")).contains("unknown field"));
+        assert!(error("", "[[listeners.rules]]\nbackend = \"ssh\"", "")
+            .contains("listeners[0].rules[0]"));
+    }
+
+    #[test]
+    fn limits_bandwidth_and_quota() {
+        assert!(error("", "", "[limits]\nmax_connections = 0").contains("max_connections"));
+        assert!(error("", "", "[limits]\nper_ip = 0").contains("per_ip"));
+        assert!(error("", "", "[limits]\nreject_status = \"\"").contains("reject_status"));
+        assert!(error("", "", "[bandwidth.per_ip]\nburst_kib = 10").contains("informe up_kbit"));
+        assert!(error("", "", "[bandwidth.total]\nup_kbit = 0").contains("total.up_kbit"));
+        assert!(
+            error("", "[listeners.bandwidth.per_session]\ndown_kbit = 0", "")
+                .contains("listeners[0].bandwidth.per_session.down_kbit")
+        );
+
+        assert!(error("", "", "[quota]\nperiod = \"monthly\"").contains("informe limit_gib"));
+        assert!(error("", "", "[quota]\nlimit_gib = 1.0\nreset_day = 29").contains("entre 1 e 28"));
+        assert!(error(
+            "",
+            "",
+            "[quota]\nlimit_gib = 1.0\nperiod = \"weekly\"\nreset_day = 8"
+        )
+        .contains("entre 1 e 7"));
+        assert!(error("", "", "[quota]\nlimit_gib = -1.0").contains("não negativos"));
+        assert!(error("", "", "[quota]\nlimit_gib = 1.0\nflush_secs = 0").contains("flush_secs"));
+        assert_eq!(
+            validate(
+                "",
+                "",
+                "[quota]\nperiod = \"daily\"\n[quota.ips]\n\"10.0.0.1\" = 0.5"
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn auth_section() {
+        let auth = |body: &str| {
+            format!(
+                "[auth]\n{}\n[[auth.users]]\nname = \"ana\"\npassword = \"x\"",
+                body
+            )
+        };
+        assert_eq!(validate("", "", &auth("")), Ok(()));
+        assert!(error("", "", &auth("methods = []")).contains("ao menos um método"));
+        assert!(error("", "", &auth("header = \"X Token\"")).contains("auth.header"));
+        assert!(error("", "", &auth("realm = \"a\\\"b\"")).contains("auth.realm"));
+        assert!(error("", "", &auth("reject_status = \"\"")).contains("auth.reject_status"));
+        assert!(error("", "", &format!("{}\nquota_gib = 1.0", auth("")))
+            .contains("auth.users[0].quota_gib: a configuração não tem a seção [quota]"));
+        assert!(
+            error("", "", &format!("[quota]\n{}\nquota_gib = -1.0", auth("")))
+                .contains("não negativo")
+        );
+        assert_eq!(
+            validate("", "", &format!("[quota]\n{}\nquota_gib = 1.0", auth(""))),
+            Ok(())
+        );
+        assert!(error(
+            "",
+            "",
+            &format!("{}\n[auth.users.bandwidth]\nup_kbit = 0", auth(""))
+        )
+        .contains("auth.users[0].bandwidth.up_kbit"));
+    }
+}
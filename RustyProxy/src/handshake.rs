@@ -0,0 +1,443 @@
+use crate::sniff;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{timeout, Duration};
+
+// Maior fim de cabeçalho ("\n\r\n") depois da quebra que encerra o último campo.
+const HEADER_END_TAIL: usize = 3;
+// Cabeçalhos típicos cabem em uma leitura; os maiores crescem até max_len.
+const READ_CHUNK: usize = 2048;
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    WebSocket,
+    Connect,
+    Http,
+    Raw,
+}
+
+struct Request {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+pub struct Handshake {
+    pub kind: Kind,
+    pub websocket_key: Option<String>,
+    pub method: Option<String>,
+    // Host sem porta, em minúsculas, e caminho da requisição, usados pelas regras.
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub head: Vec<u8>,
+    // Bytes que chegaram depois do fim do cabeçalho e pertencem ao backend.
+    pub pending: Vec<u8>,
+    // O cliente não enviou nada dentro de `first_read_timeout`.
+    pub timed_out: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn host_and_path(&self) -> (Option<String>, Option<String>) {
+        if self.method == "CONNECT" {
+            return (Some(normalize_host(&self.target)), None);
+        }
+
+        let (target_host, path) = match self.target.split_once("://") {
+            Some((_, rest)) => match rest.find('/') {
+                Some(pos) => (Some(&rest[..pos]), &rest[pos..]),
+                None => (Some(rest), "/"),
+            },
+            None => (None, self.target.as_str()),
+        };
+        let host = self.header("Host").or(target_host).map(normalize_host);
+        (host, Some(path.to_string()))
+    }
+
+    fn header_has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().eq_ignore_ascii_case(token))
+        })
+    }
+}
+
+impl Handshake {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn response(&self, status: &str) -> Option<String> {
+        match self.kind {
+            Kind::WebSocket => {
+                let mut response = format!(
+                    "HTTP/1.1 101 {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n",
+                    status
+                );
+                if let Some(key) = &self.websocket_key {
+                    response.push_str(&format!(
+                        "Sec-WebSocket-Accept: {}\r\n",
+                        websocket_accept(key)
+                    ));
+                }
+                response.push_str("\r\n");
+                Some(response)
+            }
+            Kind::Connect => Some("HTTP/1.1 200 Connection Established\r\n\r\n".to_string()),
+            Kind::Http => Some(format!("HTTP/1.1 200 {}\r\n\r\n", status)),
+            Kind::Raw => None,
+        }
+    }
+}
+
+// Sem dados dentro de `first_read_timeout` o cliente é tratado como tráfego cru
+// (ex.: protocolos em que o servidor fala primeiro).
+pub async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_len: usize,
+    first_read_timeout: Duration,
+) -> Result<Handshake, Error> {
+    let mut chunk = [0; READ_CHUNK];
+    let first_read = chunk.len().min(max_len);
+    let (bytes_read, timed_out) =
+        match timeout(first_read_timeout, stream.read(&mut chunk[..first_read])).await {
+            Ok(result) => (result?, false),
+            Err(_) => (0, true),
+        };
+    let mut data = chunk[..bytes_read].to_vec();
+
+    if !sniff::looks_like_http(&data) {
+        return Ok(Handshake {
+            kind: Kind::Raw,
+            websocket_key: None,
+            method: None,
+            host: None,
+            path: None,
+            headers: Vec::new(),
+            head: Vec::new(),
+            pending: data,
+            timed_out,
+        });
+    }
+
+    // Payloads divididos mandam várias requisições de disfarce antes da que pede túnel;
+    // todas são consumidas até ela, e o que vem depois já é do túnel.
+    let mut requests = Vec::new();
+    let mut head_start = 0;
+    let mut search_from = 0;
+    loop {
+        if let Some(end) = find_header_end(&data[search_from..]) {
+            let head_len = search_from + end;
+            let request = parse_request(&data[head_start..head_len])?;
+            let tunnel = classify(&request) != Kind::Http;
+            requests.push(request);
+            head_start = head_len;
+            search_from = head_len;
+            if !tunnel && sniff::looks_like_http(&data[head_len..]) {
+                continue;
+            }
+            let pending = data.split_off(head_len);
+            return Ok(handshake_from(requests, data, pending, timed_out));
+        }
+
+        if data.len() >= max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cabeçalho HTTP excede o tamanho do buffer",
+            ));
+        }
+
+        // Recomeça a busca um pouco antes para achar o delimitador dividido entre leituras.
+        search_from = data
+            .len()
+            .saturating_sub(HEADER_END_TAIL - 1)
+            .max(head_start);
+        let room = (max_len - data.len()).min(chunk.len());
+        let bytes_read = stream.read(&mut chunk[..room]).await?;
+        if bytes_read == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "conexão encerrada antes do fim do cabeçalho HTTP",
+            ));
+        }
+        data.extend_from_slice(&chunk[..bytes_read]);
+    }
+}
+
+// Quem decide o tipo é a última requisição que pede túnel (CONNECT ou Upgrade); as
+// anteriores costumam ser só disfarce. Os cabeçalhos de todas ficam disponíveis.
+fn handshake_from(
+    requests: Vec<Request>,
+    head: Vec<u8>,
+    pending: Vec<u8>,
+    timed_out: bool,
+) -> Handshake {
+    let main = requests
+        .iter()
+        .rposition(|request| classify(request) != Kind::Http)
+        .unwrap_or(requests.len() - 1);
+    let request = &requests[main];
+    let (host, path) = request.host_and_path();
+    Handshake {
+        kind: classify(request),
+        websocket_key: request.header("Sec-WebSocket-Key").map(str::to_string),
+        method: Some(request.method.clone()),
+        host,
+        path,
+        headers: requests
+            .into_iter()
+            .flat_map(|request| request.headers)
+            .collect(),
+        head,
+        pending,
+        timed_out,
+    }
+}
+
+fn websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+fn classify(request: &Request) -> Kind {
+    if request.method == "CONNECT" {
+        Kind::Connect
+    } else if request.header_has_token("Upgrade", "websocket") {
+        Kind::WebSocket
+    } else {
+        Kind::Http
+    }
+}
+
+fn parse_request(head: &[u8]) -> Result<Request, Error> {
+    let bad = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let text = std::str::from_utf8(head).map_err(|_| bad("cabeçalho HTTP não é UTF-8"))?;
+    // Alguns injetores usam só LF como quebra de linha.
+    let mut lines = text
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(bad("linha de requisição HTTP inválida")),
+    };
+    if target.is_empty() || !version.starts_with("HTTP/") {
+        return Err(bad("linha de requisição HTTP inválida"));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad("cabeçalho HTTP inválido"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+    })
+}
+
+fn normalize_host(host: &str) -> String {
+    let without_port = match host.rsplit_once(':') {
+        Some((name, port))
+            if port.bytes().all(|b| b.is_ascii_digit())
+                && (!name.contains(':') || name.ends_with(']')) =>
+        {
+            name
+        }
+        _ => host,
+    };
+    without_port.to_ascii_lowercase()
+}
+
+// Posição logo depois da linha vazia que encerra o cabeçalho ("\r\n\r\n" ou "\n\n").
+fn find_header_end(data: &[u8]) -> Option<usize> {
+    (0..data.len()).find_map(|i| match &data[i..] {
+        [b'\n', b'\n', ..] => Some(i + 2),
+        [b'\n', b'\r', b'\n', ..] => Some(i + 3),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LEN: usize = 16 * 1024;
+    const PEEK: Duration = Duration::from_secs(1);
+
+    async fn read(parts: &[&[u8]]) -> Result<Handshake, Error> {
+        let mut stream: Box<dyn AsyncRead + Unpin> = Box::new(tokio::io::empty());
+        for part in parts.iter().rev() {
+            stream = Box::new(part.chain(stream));
+        }
+        read_handshake(&mut stream, MAX_LEN, PEEK).await
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade() {
+        let handshake = read(&[b"GET /chat HTTP/1.1\r\nHost: Exemplo.com:80\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\nSSH-2.0-x"])
+            .await
+            .unwrap();
+        assert_eq!(handshake.kind, Kind::WebSocket);
+        assert_eq!(handshake.host.as_deref(), Some("exemplo.com"));
+        assert_eq!(handshake.path.as_deref(), Some("/chat"));
+        assert_eq!(handshake.pending, b"SSH-2.0-x");
+        let response = handshake.response("@Teste").unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 @Teste\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_split_across_reads() {
+        let handshake = read(&[
+            b"CONNECT 127.0.0.1:22 HT",
+            b"TP/1.1\r\nHost: a\r",
+            b"\n\r",
+            b"\nSSH-",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(handshake.kind, Kind::Connect);
+        assert_eq!(handshake.host.as_deref(), Some("127.0.0.1"));
+        assert_eq!(
+            handshake.head,
+            b"CONNECT 127.0.0.1:22 HTTP/1.1\r\nHost: a\r\n\r\n"
+        );
+        assert_eq!(handshake.pending, b"SSH-");
+    }
+
+    #[tokio::test]
+    async fn lf_only_head() {
+        let handshake = read(&[b"GET / HTTP/1.1\nHost: x\nUpgrade: websocket\n", b"\nabc"])
+            .await
+            .unwrap();
+        assert_eq!(handshake.kind, Kind::WebSocket);
+        assert_eq!(handshake.header("host"), Some("x"));
+        assert_eq!(handshake.pending, b"abc");
+    }
+
+    #[tokio::test]
+    async fn split_payload_consumes_every_request() {
+        let handshake = read(&[
+            b"GET http://x/ HTTP/1.1\r\nHost: x\r\nX-Auth-Token: t\r\n\r\nCONNECT 127.0.0.1:22 HTTP/1.1\r\n",
+            b"\r\nSSH-2.0-x",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(handshake.kind, Kind::Connect);
+        assert_eq!(handshake.method.as_deref(), Some("CONNECT"));
+        assert_eq!(handshake.header("X-Auth-Token"), Some("t"));
+        assert_eq!(handshake.pending, b"SSH-2.0-x");
+
+        // Sem pedido de túnel, vale a última requisição.
+        let handshake = read(&[b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n"])
+            .await
+            .unwrap();
+        assert_eq!(handshake.kind, Kind::Http);
+        assert_eq!(handshake.path.as_deref(), Some("/b"));
+        assert!(handshake.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn bytes_after_tunnel_request_belong_to_tunnel() {
+        let handshake =
+            read(&[b"CONNECT x:80 HTTP/1.1\r\n\r\nGET /data HTTP/1.1\r\nHost: x\r\n\r\n"])
+                .await
+                .unwrap();
+        assert_eq!(handshake.kind, Kind::Connect);
+        assert_eq!(handshake.pending, b"GET /data HTTP/1.1\r\nHost: x\r\n\r\n");
+
+        let handshake =
+            read(&[b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\nPOST /up HTTP/1.1\r\n\r\n"])
+                .await
+                .unwrap();
+        assert_eq!(handshake.kind, Kind::WebSocket);
+        assert_eq!(handshake.path.as_deref(), Some("/"));
+        assert_eq!(handshake.pending, b"POST /up HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn raw_traffic_is_untouched() {
+        let handshake = read(&[b"SSH-2.0-OpenSSH\r\n"]).await.unwrap();
+        assert_eq!(handshake.kind, Kind::Raw);
+        assert!(handshake.response("x").is_none());
+        assert_eq!(handshake.pending, b"SSH-2.0-OpenSSH\r\n");
+    }
+
+    #[tokio::test]
+    async fn invalid_and_truncated_heads() {
+        let e = read(&[b"GET /\r\n\r\n"]).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = read(&[b"GET / HTTP/1.1\r\nsem-dois-pontos\r\n\r\n"])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = read(&[b"GET / HTTP/1.1\r\nHost: x\r\n"])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        let huge = [b"GET / HTTP/1.1\r\nX: ".as_slice(), &[b'a'; MAX_LEN]].concat();
+        let e = read(&[&huge]).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn header_end_variants() {
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\r\n\r\nx"), Some(18));
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\n\nx"), Some(16));
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\nHost: a\n\r\n"), Some(25));
+        assert_eq!(find_header_end(b"GET / HTTP/1.1\r\nHost: a\r\n"), None);
+    }
+
+    #[test]
+    fn absolute_targets_and_host_header() {
+        let request = parse_request(b"GET http://Alvo.com:8080/p?q=1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            request.host_and_path(),
+            (Some("alvo.com".to_string()), Some("/p?q=1".to_string()))
+        );
+        let request =
+            parse_request(b"GET http://alvo.com HTTP/1.1\r\nHost: outro\r\n\r\n").unwrap();
+        assert_eq!(
+            request.host_and_path(),
+            (Some("outro".to_string()), Some("/".to_string()))
+        );
+    }
+
+    #[test]
+    fn normalizes_hosts() {
+        assert_eq!(normalize_host("Exemplo.COM"), "exemplo.com");
+        assert_eq!(normalize_host("exemplo.com:443"), "exemplo.com");
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]");
+        assert_eq!(normalize_host("::1"), "::1");
+        assert_eq!(normalize_host("exemplo.com:abc"), "exemplo.com:abc");
+    }
+}
@@ -0,0 +1,132 @@
+use crate::bandwidth::Bandwidth;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::quota::Quotas;
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::time::{self, Duration};
+use tracing::error;
+
+// Clientes IPv6 costumam receber um /64 inteiro, então o limite vale para o prefixo.
+const IPV6_LIMIT_PREFIX: u32 = 64;
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Limites compartilhados por todos os listeners.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    pub global: Option<Arc<Semaphore>>,
+    pub per_ip: Option<Arc<IpLimiter>>,
+    pub bandwidth: Arc<Bandwidth>,
+    pub quotas: Option<Arc<Quotas>>,
+}
+
+pub struct IpLimiter {
+    max: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+    metrics: Arc<Metrics>,
+}
+
+// Libera a vaga do IP quando a sessão termina.
+pub struct IpPermit {
+    limiter: Arc<IpLimiter>,
+    key: IpAddr,
+}
+
+// Erros de accept como EMFILE se repetem até algum descritor ser liberado; a espera
+// entre tentativas dobra a cada erro seguido.
+pub struct AcceptBackoff {
+    delay: Duration,
+}
+
+impl ConnectionLimits {
+    pub fn new(config: &Config, metrics: &Arc<Metrics>, quotas: Option<Arc<Quotas>>) -> Self {
+        ConnectionLimits {
+            global: config
+                .limits
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            per_ip: config
+                .limits
+                .per_ip
+                .map(|max| Arc::new(IpLimiter::new(max, metrics.clone()))),
+            bandwidth: Arc::new(Bandwidth::new(config)),
+            quotas,
+        }
+    }
+}
+
+impl IpLimiter {
+    pub fn new(max: usize, metrics: Arc<Metrics>) -> Self {
+        IpLimiter {
+            max,
+            counts: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpPermit> {
+        let key = limit_key(ip);
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        if *count == 0 {
+            self.metrics.limited_ips.fetch_add(1, Ordering::Relaxed);
+        }
+        *count += 1;
+        Some(IpPermit {
+            limiter: self.clone(),
+            key,
+        })
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+                self.limiter
+                    .metrics
+                    .limited_ips
+                    .fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl AcceptBackoff {
+    pub fn new() -> Self {
+        AcceptBackoff {
+            delay: ACCEPT_BACKOFF_MIN,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = ACCEPT_BACKOFF_MIN;
+    }
+
+    pub async fn wait(&mut self, e: &Error, server: &str) {
+        error!(error = %e, server, retry_ms = self.delay.as_millis() as u64, "Erro ao aceitar conexão");
+        time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(ACCEPT_BACKOFF_MAX);
+    }
+}
+
+pub fn limit_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << (128 - IPV6_LIMIT_PREFIX);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+        ip => ip,
+    }
+}
@@ -0,0 +1,97 @@
+use crate::access_log;
+use crate::config::{invalid, AccessLogConfig, LogConfig, LogFormat, LogRotation};
+use std::env;
+use std::io::{self, Error, IsTerminal};
+use std::path::Path;
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, FilterExt, Targets};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// RUST_LOG, quando definido, tem precedência sobre log.level. O guard devolvido
+// precisa viver até o fim do processo para o access log ser descarregado.
+pub fn init(
+    log: &LogConfig,
+    access_log: Option<&AccessLogConfig>,
+) -> Result<Option<WorkerGuard>, Error> {
+    let directives = match env::var("RUST_LOG") {
+        Ok(value) if !value.is_empty() => value,
+        _ => log.level.clone(),
+    };
+    let filter = EnvFilter::try_new(&directives)
+        .map_err(|e| invalid(format!("nível de log inválido '{}': {}", directives, e)))?
+        .and(filter_fn(|meta| meta.target() != access_log::TARGET));
+
+    let mut layers: Vec<BoxedLayer> = vec![match log.format {
+        LogFormat::Text => fmt::layer()
+            .with_target(false)
+            .with_ansi(io::stdout().is_terminal())
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_target(false)
+            .with_filter(filter)
+            .boxed(),
+    }];
+
+    let mut guard = None;
+    if let Some(access_log) = access_log {
+        let (writer, worker_guard) = match &access_log.path {
+            Some(path) => tracing_appender::non_blocking(rolling_file(path, access_log.rotation)?),
+            None => tracing_appender::non_blocking(io::stdout()),
+        };
+        guard = Some(worker_guard);
+
+        let access_filter = Targets::new().with_target(access_log::TARGET, Level::INFO);
+        layers.push(match access_log.format {
+            LogFormat::Text => fmt::layer()
+                .with_writer(writer)
+                .with_target(false)
+                .with_level(false)
+                .with_ansi(false)
+                .with_filter(access_filter)
+                .boxed(),
+            LogFormat::Json => fmt::layer()
+                .json()
+                .with_writer(writer)
+                .with_target(false)
+                .with_level(false)
+                .with_current_span(false)
+                .with_span_list(false)
+                .with_filter(access_filter)
+                .boxed(),
+        });
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .map_err(|e| invalid(format!("falha ao iniciar o log: {}", e)))?;
+    Ok(guard)
+}
+
+fn rolling_file(path: &Path, rotation: LogRotation) -> Result<RollingFileAppender, Error> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| invalid(format!("access_log.path inválido: {}", path.display())))?;
+    let rotation = match rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name.to_string_lossy())
+        .build(dir)
+        .map_err(|e| invalid(format!("access_log {}: {}", path.display(), e)))
+}
@@ -1,137 +1,730 @@
+mod access_log;
+mod admin;
+mod auth;
+mod bandwidth;
+mod buffer;
+mod config;
+mod handshake;
+mod limits;
+mod logging;
+mod metrics;
+mod quota;
+mod relay;
+mod routing;
+mod session;
+mod sniff;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stream;
+mod tls;
+mod websocket;
+
+use config::{Config, RejectMode};
+use handshake::{Handshake, Kind};
+use limits::{AcceptBackoff, ConnectionLimits};
+use metrics::Metrics;
+use quota::Quotas;
+use routing::RouteInput;
+use session::{CloseReason, Direction, Session, Sessions};
+use sniff::Protocol;
 use std::env;
-use std::io::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use stream::ClientStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::{timeout, Duration};
-const BUFFER_SIZE: usize = 32768;
-const SSH_KEYWORD: &str = "SSH";
-const SSH_TARGET_ADDR: &str = "127.0.0.1:22";       
-const OPENVPN_TARGET_ADDR: &str = "127.0.0.1:1194"; 
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use websocket::FrameReader;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Established {
+    Backend(TcpStream, Option<FrameReader>),
+    Closed(CloseReason),
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let port = get_port();
-    let listener = TcpListener::bind(format!("[::]:{}", port)).await?;
-    println!("Iniciando serviço na porta: {}", port);
-    start_http(listener).await;
+    if env::args().any(|arg| arg == "--quota-usage") {
+        let printed = load_config().and_then(|config| match &config.quota {
+            Some(quota) => {
+                let users = config.auth.as_ref().map_or(&[][..], |auth| &auth.users);
+                quota::print_usage(quota, users)
+            }
+            None => Err(config::invalid("a configuração não tem a seção [quota]")),
+        });
+        if let Err(e) = printed {
+            eprintln!("Erro: {}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Usado pelo menu antes de reiniciar o serviço com uma configuração nova.
+    if env::args().any(|arg| arg == "--check-config") {
+        let checked = load_config().and_then(|config| load_tls_acceptors(&config));
+        if let Err(e) = checked {
+            eprintln!("Erro na configuração: {}", e);
+            process::exit(1);
+        }
+        println!("Configuração válida");
+        return Ok(());
+    }
+
+    let startup = load_config().and_then(|config| {
+        let acceptors = load_tls_acceptors(&config)?;
+        let metrics_addr = get_metrics_addr()?;
+        let admin_addr = find_arg_value("--admin-listen")?
+            .map(|value| admin::parse_addr(&value))
+            .transpose()?;
+        let log_guard = logging::init(&config.log, config.access_log.as_ref())?;
+        let quotas = config.quota.as_ref().map(Quotas::load).transpose()?;
+        Ok((
+            config,
+            acceptors,
+            metrics_addr,
+            admin_addr,
+            log_guard,
+            quotas,
+        ))
+    });
+    let (config, acceptors, metrics_addr, admin_addr, _log_guard, quotas) = match startup {
+        Ok(startup) => startup,
+        Err(e) => {
+            eprintln!("Erro na configuração: {}", e);
+            process::exit(1);
+        }
+    };
+    let config = Arc::new(config);
+
+    let metrics = Arc::new(Metrics::default());
+    let mut listeners = Vec::new();
+    for ((index, listener_config), acceptor) in config.listeners.iter().enumerate().zip(acceptors) {
+        let port = listener_config.port;
+        let listener = TcpListener::bind(format!("[::]:{}", port)).await?;
+        info!(port, tls = acceptor.is_some(), "Iniciando serviço na porta");
+        metrics.listener(port);
+        listeners.push((index, listener, acceptor));
+    }
+
+    let mut tasks = Vec::new();
+    if let Some(addr) = metrics_addr {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "Métricas disponíveis em /metrics");
+        tasks.push(tokio::spawn(metrics::serve(listener, metrics.clone())));
+    }
+    let sessions = Arc::new(Sessions::default());
+    let quotas = quotas.map(Arc::new);
+    if let Some(quotas) = &quotas {
+        tasks.push(tokio::spawn(quotas.clone().run()));
+    }
+    let limits = ConnectionLimits::new(&config, &metrics, quotas.clone());
+    if let Some(addr) = admin_addr {
+        let listener = admin::bind(&addr).await?;
+        tasks.push(tokio::spawn(admin::serve(listener, sessions.clone())));
+    }
+    for (index, listener, acceptor) in listeners {
+        tasks.push(tokio::spawn(start_http(
+            listener,
+            acceptor,
+            config.clone(),
+            metrics.clone(),
+            sessions.clone(),
+            limits.clone(),
+            index,
+        )));
+    }
+    let finished = tokio::select! {
+        result = wait_all(tasks) => result,
+        _ = shutdown_signal() => {
+            info!("Sinal de encerramento recebido");
+            Ok(())
+        }
+    };
+    // O consumo desde a última gravação periódica se perderia ao sair.
+    if let Some(quotas) = &quotas {
+        if let Err(e) = quotas.save() {
+            error!(error = %e, "Erro ao gravar o consumo de cotas");
+        }
+    }
+    finished
+}
+
+async fn wait_all(tasks: Vec<JoinHandle<()>>) -> Result<(), Error> {
+    for task in tasks {
+        task.await?;
+    }
     Ok(())
 }
 
-async fn start_http(listener: TcpListener) {
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!(error = %e, "Não foi possível tratar SIGTERM");
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+async fn start_http(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    sessions: Arc<Sessions>,
+    limits: ConnectionLimits,
+    listener_index: usize,
+) {
+    let listener_metrics = metrics.listener(config.listeners[listener_index].port);
+    let mut backoff = AcceptBackoff::new();
     loop {
+        // Com o limite global atingido, nem aceita: os clientes esperam no backlog do kernel.
+        let connection_permit = match &limits.global {
+            Some(semaphore) => {
+                if semaphore.available_permits() == 0 {
+                    debug!("Limite global de conexões atingido; accept pausado");
+                    listener_metrics
+                        .accept_pauses
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                semaphore.clone().acquire_owned().await.ok()
+            }
+            None => None,
+        };
         match listener.accept().await {
-            Ok((client_stream, addr)) => {
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(client_stream).await {
-                        println!("Erro ao processar cliente {}: {}", addr, e);
+            Ok((tcp_stream, addr)) => {
+                backoff.reset();
+                let ip_permit = match &limits.per_ip {
+                    Some(limiter) => match limiter.try_acquire(addr.ip()) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            debug!(peer = %addr, "Limite de sessões por IP atingido; conexão recusada");
+                            listener_metrics
+                                .ip_limit_rejections
+                                .fetch_add(1, Ordering::Relaxed);
+                            reject_connection(tcp_stream, &config, acceptor.is_some());
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let config = config.clone();
+                let acceptor = acceptor.clone();
+                let sessions = sessions.clone();
+                let session = Arc::new(Session::new(
+                    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+                    addr,
+                    config.listeners[listener_index].port,
+                    metrics.clone(),
+                    &limits,
+                    listener_index,
+                ));
+                let span = info_span!(
+                    "conn",
+                    id = session.id,
+                    peer = %addr,
+                    port = session.port,
+                    user = field::Empty,
+                    protocol = field::Empty,
+                    backend = field::Empty,
+                );
+                sessions.insert(session.clone());
+                tokio::spawn(
+                    async move {
+                        let serve =
+                            serve_session(tcp_stream, acceptor, &session, config, listener_index);
+                        let reason = tokio::select! {
+                            reason = serve => reason,
+                            _ = session.killed() => {
+                                info!("Sessão encerrada pela API de administração");
+                                CloseReason::Killed
+                            }
+                        };
+                        sessions.remove(session.id);
+                        access_log::record(&session, reason);
+                        drop((ip_permit, connection_permit));
                     }
-                });
+                    .instrument(span),
+                );
             }
-            Err(e) => {
-                println!("Erro ao aceitar conexão: {}", e);
+            Err(e) => backoff.wait(&e, "proxy").await,
+        }
+    }
+}
+
+// Portas TLS apenas fecham a conexão, já que uma resposta HTTP iria em claro.
+fn reject_connection(mut tcp_stream: TcpStream, config: &Config, tls: bool) {
+    if config.limits.reject != RejectMode::Http || tls {
+        return;
+    }
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        config.limits.reject_status
+    );
+    tokio::spawn(async move {
+        let _ = timeout(REJECT_WRITE_TIMEOUT, async {
+            tcp_stream.write_all(response.as_bytes()).await?;
+            tcp_stream.shutdown().await
+        })
+        .await;
+    });
+}
+
+async fn serve_session(
+    tcp_stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    session: &Session,
+    config: Arc<Config>,
+    listener_index: usize,
+) -> CloseReason {
+    let deadline = session.started + Duration::from_millis(config.timeouts.handshake_ms);
+    let client_stream = match acceptor {
+        Some(acceptor) => match timeout_at(deadline, acceptor.accept(tcp_stream)).await {
+            Ok(Ok(tls_stream)) => ClientStream::Tls(Box::new(tls_stream)),
+            Ok(Err(e)) => {
+                warn!(error = %e, "Erro no handshake TLS");
+                session
+                    .metrics
+                    .handshake_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                return CloseReason::TlsError;
             }
+            Err(_) => return handshake_timed_out(session),
+        },
+        None => ClientStream::Tcp(tcp_stream),
+    };
+    let result = handle_client(client_stream, session, config, listener_index, deadline).await;
+    let duration_ms = session.started.elapsed().as_millis() as u64;
+    match result {
+        Ok(reason) => {
+            info!(duration_ms, %reason, "Conexão encerrada");
+            reason
+        }
+        Err(e) => {
+            warn!(duration_ms, error = %e, "Erro ao processar cliente");
+            CloseReason::from_error(&e)
         }
     }
 }
 
-async fn handle_client(mut client_stream: TcpStream) -> Result<(), Error> {
-    let status = get_status();
-    client_stream
-        .write_all(format!("HTTP/1.1 101 {}\r\n\r\n", status).as_bytes())
-        .await?;
+async fn handle_client(
+    mut client_stream: ClientStream,
+    session: &Session,
+    config: Arc<Config>,
+    listener_index: usize,
+    deadline: Instant,
+) -> Result<CloseReason, Error> {
+    let established = timeout_at(
+        deadline,
+        establish(&mut client_stream, session, &config, listener_index),
+    )
+    .await;
+    let (server_stream, frame_reader) = match established {
+        Ok(Ok(Established::Backend(server_stream, frame_reader))) => (server_stream, frame_reader),
+        Ok(Ok(Established::Closed(reason))) => return Ok(reason),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Ok(handshake_timed_out(session)),
+    };
 
-    let mut buffer = vec![0; BUFFER_SIZE];
-    client_stream.read(&mut buffer).await?;
-    client_stream
-        .write_all(format!("HTTP/1.1 200 {}\r\n\r\n", status).as_bytes())
-        .await?;
+    if let Some(reader) = frame_reader {
+        return websocket::relay(client_stream, server_stream, reader, session, &config).await;
+    }
 
-    let peek_result = timeout(Duration::from_secs(1), peek_stream(&mut client_stream)).await;
+    relay::relay(client_stream, server_stream, session, &config).await
+}
 
-    let data_to_check = match peek_result {
-        Ok(Ok(data)) => data,
-        Ok(Err(e)) => {
-            println!("Erro ao espiar o stream: {}", e);
-            String::new()
-        }
-        Err(_) => {
-            println!("Tempo limite excedido ao espiar o stream.");
-            String::new()
+// Tudo até o backend estar conectado e com os dados iniciais: handshake, espiada,
+// roteamento e connect. Roda sob o prazo de timeouts.handshake_ms.
+async fn establish(
+    client_stream: &mut ClientStream,
+    session: &Session,
+    config: &Config,
+    listener_index: usize,
+) -> Result<Established, Error> {
+    if let Some(closed) = quota_exhausted(session) {
+        return Ok(closed);
+    }
+    let listener = &config.listeners[listener_index];
+    let peer = session.peer.ip().to_canonical();
+    let terminated_sni = client_stream.sni().map(str::to_ascii_lowercase);
+    let peek_timeout = Duration::from_millis(config.timeouts.peek_ms);
+    let handshake =
+        handshake::read_handshake(client_stream, config.buffer_size, peek_timeout).await;
+    let mut handshake = checked_handshake(client_stream, session, handshake).await?;
+    if handshake.timed_out {
+        peek_timed_out(session);
+    }
+
+    let forward_for = |handshake: &Handshake| {
+        if handshake.kind == Kind::Raw {
+            return None;
         }
+        let input = RouteInput {
+            data: &handshake.head,
+            protocol: Protocol::Http,
+            host: handshake.host.as_deref(),
+            path: handshake.path.as_deref(),
+            sni: terminated_sni.as_deref(),
+            alpn: &[],
+            peer,
+            port: listener.port,
+        };
+        routing::select_forward(config, listener, &input)
     };
 
-    let addr_proxy: &str;
-    if data_to_check.contains(SSH_KEYWORD) || data_to_check.is_empty() {
-        addr_proxy = SSH_TARGET_ADDR;
-    } else {
-        addr_proxy = OPENVPN_TARGET_ADDR;
+    // Com [split]/[delay_split] a requisição de túnel só chega depois da resposta ao
+    // disfarce; ela volta para o parser de cabeçalho em vez de seguir para o backend.
+    let mut answered = false;
+    let mut sniffed = false;
+    while handshake.kind == Kind::Http
+        && handshake.pending.is_empty()
+        && forward_for(&handshake).is_none()
+    {
+        if let Some(response) = handshake.response(&listener.status) {
+            client_stream.write_all(response.as_bytes()).await?;
+        }
+        answered = true;
+        sniffed = true;
+
+        let data = match timeout(peek_timeout, sniff_stream(client_stream)).await {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => {
+                warn!(error = %e, "Erro ao espiar o stream");
+                break;
+            }
+            Err(_) => {
+                peek_timed_out(session);
+                break;
+            }
+        };
+        if !sniff::looks_like_http(&data) {
+            handshake.pending = data;
+            break;
+        }
+
+        let next = handshake::read_handshake(
+            &mut data.as_slice().chain(&mut *client_stream),
+            config.buffer_size,
+            peek_timeout,
+        )
+        .await;
+        let mut next = checked_handshake(client_stream, session, next).await?;
+        // Como no payload recebido de uma vez, os cabeçalhos do disfarce continuam valendo.
+        handshake.headers.append(&mut next.headers);
+        next.headers = mem::take(&mut handshake.headers);
+        handshake = next;
+        answered = false;
+        sniffed = false;
+    }
+
+    if let Some(auth) = config.auth_for(listener) {
+        // Tráfego cru não tem onde levar credenciais e é sempre recusado.
+        let user = match handshake.kind {
+            Kind::Raw => None,
+            _ => auth::authenticate(auth, &handshake).await,
+        };
+        let Some(user) = user else {
+            warn!("Autenticação recusada");
+            session
+                .metrics
+                .auth_failures
+                .fetch_add(1, Ordering::Relaxed);
+            if handshake.kind != Kind::Raw && !answered {
+                if let Some(response) = auth::rejection(auth) {
+                    let _ = client_stream.write_all(response.as_bytes()).await;
+                }
+            }
+            return Ok(Established::Closed(CloseReason::AuthFailed));
+        };
+        Span::current().record("user", user);
+        let user_config = auth.users.iter().find(|config| config.name == user);
+        session.set_user(
+            user,
+            user_config.and_then(|config| config.bandwidth.as_ref()),
+            user_config.and_then(|config| config.quota_gib),
+        );
+        if let Some(closed) = quota_exhausted(session) {
+            return Ok(closed);
+        }
     }
 
+    // Dados já recebidos do cliente que devem chegar ao backend antes do relay.
+    let mut initial_data = Vec::new();
+    let mut frame_reader = None;
+
+    let forward = forward_for(&handshake);
+
+    let backend = match forward {
+        Some(backend) => {
+            record_protocol(session, Protocol::Http);
+            initial_data = mem::take(&mut handshake.head);
+            initial_data.append(&mut handshake.pending);
+            backend
+        }
+        None => {
+            if !answered {
+                if let Some(response) = handshake.response(&listener.status) {
+                    client_stream.write_all(response.as_bytes()).await?;
+                }
+            }
+
+            if handshake.kind == Kind::WebSocket && listener.websocket_frames {
+                let mut reader = FrameReader::new(mem::take(&mut handshake.pending));
+                let first_payload = websocket::read_first_payload(
+                    client_stream,
+                    &mut reader,
+                    session,
+                    config.buffer_size,
+                    peek_timeout,
+                )
+                .await;
+                match first_payload {
+                    Ok(Some(payload)) => initial_data = payload,
+                    Ok(None) => return Ok(Established::Closed(CloseReason::Eof)),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => peek_timed_out(session),
+                    Err(e) => return Err(e),
+                }
+                frame_reader = Some(reader);
+            } else if handshake.kind != Kind::Raw && handshake.pending.is_empty() && !sniffed {
+                let peek_result = timeout(peek_timeout, sniff_stream(client_stream)).await;
+
+                match peek_result {
+                    Ok(Ok(data)) => initial_data = data,
+                    Ok(Err(e)) => warn!(error = %e, "Erro ao espiar o stream"),
+                    Err(_) => peek_timed_out(session),
+                }
+            } else {
+                initial_data = mem::take(&mut handshake.pending);
+            }
+
+            let protocol = sniff::detect(&initial_data);
+            let mut client_hello = None;
+            if protocol == Protocol::Tls && frame_reader.is_none() {
+                let completed = timeout(
+                    peek_timeout,
+                    complete_tls_record(client_stream, &mut initial_data),
+                )
+                .await;
+                if let Ok(Err(e)) = completed {
+                    return Err(e);
+                }
+                client_hello = sniff::parse_client_hello(&initial_data);
+            }
+            let client_hello = client_hello.unwrap_or_default();
+            record_protocol(session, protocol);
+
+            let input = RouteInput {
+                data: &initial_data,
+                protocol,
+                host: handshake.host.as_deref(),
+                path: handshake.path.as_deref(),
+                sni: terminated_sni.as_deref().or(client_hello.sni.as_deref()),
+                alpn: &client_hello.alpn,
+                peer,
+                port: listener.port,
+            };
+            match routing::select_backend(config, listener, &input) {
+                Some(backend) => backend,
+                None => {
+                    info!("Tráfego não casou com nenhuma regra; conexão encerrada");
+                    return Ok(Established::Closed(CloseReason::NoRoute));
+                }
+            }
+        }
+    };
+    Span::current().record("backend", backend);
+    session.set_backend(backend);
+    let addr_proxy = config.backend_addr(backend);
+
     let server_connect = TcpStream::connect(addr_proxy).await;
-    let server_stream = match server_connect {
+    let mut server_stream = match server_connect {
         Ok(s) => s,
         Err(e) => {
-            println!(
-                "Erro ao iniciar conexão para o proxy {}: {}",
-                addr_proxy, e
-            );
-            return Ok(());
+            warn!(addr = addr_proxy, error = %e, "Erro ao iniciar conexão para o proxy");
+            if let Some(route) = session.route() {
+                route.connect_failures.fetch_add(1, Ordering::Relaxed);
+            }
+            return Ok(Established::Closed(CloseReason::BackendRefused));
         }
     };
 
-    let (client_read, client_write) = client_stream.into_split();
-    let (server_read, server_write) = server_stream.into_split();
+    if !initial_data.is_empty() {
+        server_stream.write_all(&initial_data).await?;
+        session.add_bytes(Direction::Up, initial_data.len());
+    }
 
-    let client_to_server = transfer_data(client_read, server_write);
-    let server_to_client = transfer_data(server_read, client_write);
+    Ok(Established::Backend(server_stream, frame_reader))
+}
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+fn record_protocol(session: &Session, protocol: Protocol) {
+    Span::current().record("protocol", field::debug(protocol));
+    session.set_protocol(protocol);
+}
 
-    Ok(())
+fn handshake_timed_out(session: &Session) -> CloseReason {
+    warn!("Tempo limite do handshake excedido; conexão encerrada");
+    session
+        .metrics
+        .handshake_timeouts
+        .fetch_add(1, Ordering::Relaxed);
+    CloseReason::HandshakeTimeout
 }
 
-async fn transfer_data(
-    mut read_stream: tokio::net::tcp::OwnedReadHalf,
-    mut write_stream: tokio::net::tcp::OwnedWriteHalf,
-) -> Result<(), Error> {
-    let mut buffer = [0; BUFFER_SIZE];
-    loop {
-        let bytes_read = read_stream.read(&mut buffer).await?;
+fn quota_exhausted(session: &Session) -> Option<Established> {
+    let quota = session.exhausted_quota()?;
+    info!(quota = %quota.key, "Cota de tráfego esgotada; conexão encerrada");
+    Some(Established::Closed(CloseReason::QuotaExceeded))
+}
+
+// Cabeçalho malformado recebe 400 e conta como falha de handshake.
+async fn checked_handshake(
+    client_stream: &mut ClientStream,
+    session: &Session,
+    handshake: Result<Handshake, Error>,
+) -> Result<Handshake, Error> {
+    match handshake {
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            session
+                .metrics
+                .handshake_failures
+                .fetch_add(1, Ordering::Relaxed);
+            let _ = client_stream.write_all(handshake::BAD_REQUEST).await;
+            Err(e)
+        }
+        result => result,
+    }
+}
+
+fn peek_timed_out(session: &Session) {
+    debug!("Tempo limite excedido ao espiar o stream");
+    session
+        .metrics
+        .peek_timeouts
+        .fetch_add(1, Ordering::Relaxed);
+}
 
+// Os bytes lidos aqui são consumidos do cliente e repassados ao backend antes do relay,
+// o que funciona também em streams TLS, onde não há peek.
+async fn sniff_stream<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, Error> {
+    let mut sniff_buffer = [0; 2048];
+    let bytes_read = stream.read(&mut sniff_buffer).await?;
+    Ok(sniff_buffer[..bytes_read].to_vec())
+}
+
+// Um ClientHello pode chegar em mais de um segmento; lê até completar o primeiro registro.
+async fn complete_tls_record<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    data: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let record_len = match sniff::tls_record_len(data) {
+        Some(len) => len,
+        None => return Ok(()),
+    };
+    let mut chunk = [0; 2048];
+    while data.len() < record_len {
+        let bytes_read = stream.read(&mut chunk).await?;
         if bytes_read == 0 {
             break;
         }
+        data.extend_from_slice(&chunk[..bytes_read]);
+    }
+    Ok(())
+}
 
-        write_stream.write_all(&buffer[..bytes_read]).await?;
+fn load_tls_acceptors(config: &Config) -> Result<Vec<Option<TlsAcceptor>>, Error> {
+    config
+        .listeners
+        .iter()
+        .map(|listener| listener.tls.as_ref().map(tls::load_acceptor).transpose())
+        .collect()
+}
+
+fn load_config() -> Result<Config, Error> {
+    match find_arg_value("--config")? {
+        Some(path) => Config::from_file(Path::new(&path)),
+        None => Config::from_args(get_listen_args()?),
     }
+}
 
-    Ok(())
+// --listen <porta>[=<status>] pode ser repetido; sem ele vale --port/--status.
+fn get_listen_args() -> Result<Vec<(u16, String)>, Error> {
+    let args: Vec<String> = env::args().collect();
+    let mut listeners = Vec::new();
+    for (i, _) in args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, arg)| *arg == "--listen")
+    {
+        let value = arg_value(&args, i)?;
+        let (port, status) = match value.split_once('=') {
+            Some((port, status)) => (port, status.to_string()),
+            None => (value, get_status()?),
+        };
+        let port = port
+            .parse()
+            .map_err(|_| config::invalid(format!("--listen: porta inválida '{}'", port)))?;
+        listeners.push((port, status));
+    }
+    if listeners.is_empty() {
+        listeners.push((get_port()?, get_status()?));
+    }
+    Ok(listeners)
 }
 
-async fn peek_stream(stream: &TcpStream) -> Result<String, Error> {
-    let mut peek_buffer = vec![0; 2048];
-    let bytes_peeked = stream.peek(&mut peek_buffer).await?;
-    let data = &peek_buffer[..bytes_peeked];
-    let data_str = String::from_utf8_lossy(data);
-    Ok(data_str.to_string())
+fn get_metrics_addr() -> Result<Option<SocketAddr>, Error> {
+    find_arg_value("--metrics-listen")?
+        .map(|value| {
+            value.parse().map_err(|_| {
+                config::invalid(format!("--metrics-listen: endereço inválido '{}'", value))
+            })
+        })
+        .transpose()
 }
 
-fn get_arg_value(arg_name: &str, default_value: &str) -> String {
+fn find_arg_value(arg_name: &str) -> Result<Option<String>, Error> {
     let args: Vec<String> = env::args().collect();
-    for i in 1..args.len() {
-        if args[i] == arg_name {
-            if i + 1 < args.len() {
-                return args[i + 1].clone();
-            }
-        }
+    match args.iter().skip(1).position(|arg| arg == arg_name) {
+        Some(i) => arg_value(&args, i + 1).map(|value| Some(value.to_string())),
+        None => Ok(None),
     }
-    default_value.to_string()
 }
 
-fn get_port() -> u16 {
-    get_arg_value("--port", "80").parse().unwrap_or(80)
+// Uma opção sem valor (no fim ou seguida de outra opção) é erro, não o valor padrão.
+fn arg_value(args: &[String], i: usize) -> Result<&str, Error> {
+    match args.get(i + 1) {
+        Some(value) if !value.starts_with("--") => Ok(value),
+        _ => Err(config::invalid(format!("{}: valor ausente", args[i]))),
+    }
+}
+
+fn get_arg_value(arg_name: &str, default_value: &str) -> Result<String, Error> {
+    Ok(find_arg_value(arg_name)?.unwrap_or_else(|| default_value.to_string()))
+}
+
+fn get_port() -> Result<u16, Error> {
+    let default_port = config::default_port();
+    Ok(get_arg_value("--port", &default_port.to_string())?
+        .parse()
+        .unwrap_or(default_port))
 }
 
-fn get_status() -> String {
-    get_arg_value("--status", "@RustyManager")
+fn get_status() -> Result<String, Error> {
+    get_arg_value("--status", &config::default_status())
 }
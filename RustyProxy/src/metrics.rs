@@ -0,0 +1,322 @@
+use crate::buffer;
+use crate::handshake;
+use crate::limits::AcceptBackoff;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::Error;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+use tracing::debug;
+
+// Limites superiores, em segundos, dos buckets do histograma de duração das sessões.
+const DURATION_BUCKETS: [u64; 9] = [1, 5, 15, 60, 300, 900, 3600, 14400, 86400];
+const REQUEST_MAX_LEN: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Nome, tipo, descrição e como ler o valor de uma métrica.
+type Family<T> = (&'static str, &'static str, &'static str, fn(&T) -> i64);
+
+#[derive(Default)]
+pub struct Metrics {
+    // IPs (ou prefixos /64) com sessões abertas sob limits.per_ip.
+    pub limited_ips: AtomicI64,
+    listeners: Mutex<BTreeMap<u16, Arc<ListenerMetrics>>>,
+    routes: Mutex<BTreeMap<(u16, String), Arc<RouteMetrics>>>,
+}
+
+#[derive(Default)]
+pub struct ListenerMetrics {
+    pub accepted: AtomicU64,
+    pub active: AtomicI64,
+    pub handshake_failures: AtomicU64,
+    pub handshake_timeouts: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub peek_timeouts: AtomicU64,
+    pub ip_limit_rejections: AtomicU64,
+    pub accept_pauses: AtomicU64,
+    pub buffer_bytes: AtomicI64,
+    pub bandwidth_delays: AtomicU64,
+    pub duration: Histogram,
+}
+
+// Contadores de uma combinação listener/backend.
+#[derive(Default)]
+pub struct RouteMetrics {
+    pub active: AtomicI64,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub connect_failures: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl Metrics {
+    pub fn listener(&self, port: u16) -> Arc<ListenerMetrics> {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.entry(port).or_default().clone()
+    }
+
+    pub fn route(&self, port: u16, backend: &str) -> Arc<RouteMetrics> {
+        let mut routes = self.routes.lock().unwrap();
+        routes
+            .entry((port, backend.to_string()))
+            .or_default()
+            .clone()
+    }
+
+    // Formato de exposição em texto do Prometheus.
+    pub fn render(&self) -> String {
+        let listeners: Vec<_> = self
+            .listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(port, metrics)| (*port, metrics.clone()))
+            .collect();
+        let routes: Vec<_> = self
+            .routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, metrics)| (key.clone(), metrics.clone()))
+            .collect();
+
+        let mut out = String::new();
+        let listener_metrics: [Family<ListenerMetrics>; 10] = [
+            (
+                "rustyproxy_connections_accepted_total",
+                "counter",
+                "Conexões aceitas.",
+                |m| load(&m.accepted),
+            ),
+            (
+                "rustyproxy_listener_sessions_active",
+                "gauge",
+                "Sessões abertas no listener, roteadas ou não.",
+                |m| m.active.load(Ordering::Relaxed),
+            ),
+            (
+                "rustyproxy_handshake_failures_total",
+                "counter",
+                "Handshakes TLS ou requisições HTTP inválidos.",
+                |m| load(&m.handshake_failures),
+            ),
+            (
+                "rustyproxy_handshake_timeouts_total",
+                "counter",
+                "Conexões encerradas por exceder timeouts.handshake_ms.",
+                |m| load(&m.handshake_timeouts),
+            ),
+            (
+                "rustyproxy_auth_failures_total",
+                "counter",
+                "Handshakes recusados por falta de credenciais válidas.",
+                |m| load(&m.auth_failures),
+            ),
+            (
+                "rustyproxy_peek_timeouts_total",
+                "counter",
+                "Tempo limite excedido ao espiar os primeiros bytes do cliente.",
+                |m| load(&m.peek_timeouts),
+            ),
+            (
+                "rustyproxy_ip_limit_rejections_total",
+                "counter",
+                "Conexões recusadas por exceder limits.per_ip.",
+                |m| load(&m.ip_limit_rejections),
+            ),
+            (
+                "rustyproxy_accept_pauses_total",
+                "counter",
+                "Vezes em que o accept pausou por limits.max_connections.",
+                |m| load(&m.accept_pauses),
+            ),
+            (
+                "rustyproxy_listener_buffer_bytes",
+                "gauge",
+                "Bytes em buffers de relay das sessões abertas no listener.",
+                |m| m.buffer_bytes.load(Ordering::Relaxed),
+            ),
+            (
+                "rustyproxy_bandwidth_delays_total",
+                "counter",
+                "Blocos repassados com atraso pelos limites de banda.",
+                |m| load(&m.bandwidth_delays),
+            ),
+        ];
+        for (name, kind, help, value) in listener_metrics {
+            header(&mut out, name, kind, help);
+            for (port, metrics) in &listeners {
+                let _ = writeln!(out, "{}{{listener=\"{}\"}} {}", name, port, value(metrics));
+            }
+        }
+
+        let route_metrics: [Family<RouteMetrics>; 4] = [
+            (
+                "rustyproxy_sessions_active",
+                "gauge",
+                "Sessões ativas por listener e backend.",
+                |m| m.active.load(Ordering::Relaxed),
+            ),
+            (
+                "rustyproxy_bytes_up_total",
+                "counter",
+                "Bytes enviados do cliente ao backend.",
+                |m| load(&m.bytes_up),
+            ),
+            (
+                "rustyproxy_bytes_down_total",
+                "counter",
+                "Bytes enviados do backend ao cliente.",
+                |m| load(&m.bytes_down),
+            ),
+            (
+                "rustyproxy_backend_connect_failures_total",
+                "counter",
+                "Falhas ao conectar no backend.",
+                |m| load(&m.connect_failures),
+            ),
+        ];
+        for (name, kind, help, value) in route_metrics {
+            header(&mut out, name, kind, help);
+            for ((port, backend), metrics) in &routes {
+                let _ = writeln!(
+                    out,
+                    "{}{{listener=\"{}\",backend=\"{}\"}} {}",
+                    name,
+                    port,
+                    backend,
+                    value(metrics)
+                );
+            }
+        }
+
+        let name = "rustyproxy_ip_limit_tracked_addresses";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "IPs (ou prefixos /64) com sessões abertas.",
+        );
+        let _ = writeln!(out, "{} {}", name, self.limited_ips.load(Ordering::Relaxed));
+
+        let name = "rustyproxy_buffer_pool_idle_bytes";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Bytes em buffers livres guardados para reuso.",
+        );
+        let _ = writeln!(out, "{} {}", name, buffer::idle_bytes());
+
+        let name = "rustyproxy_session_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Duração das sessões encerradas.",
+        );
+        for (port, metrics) in &listeners {
+            metrics.duration.render(&mut out, name, *port);
+        }
+        out
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            if secs <= bound as f64 {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, port: u16) {
+        for (bucket, bound) in self.buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{listener=\"{}\",le=\"{}\"}} {}",
+                name,
+                port,
+                bound,
+                load(bucket)
+            );
+        }
+        let count = load(&self.count);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{listener=\"{}\",le=\"+Inf\"}} {}",
+            name, port, count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{listener=\"{}\"}} {}",
+            name,
+            port,
+            load(&self.sum_ms) as f64 / 1000.0
+        );
+        let _ = writeln!(out, "{}_count{{listener=\"{}\"}} {}", name, port, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn load(counter: &AtomicU64) -> i64 {
+    counter.load(Ordering::Relaxed) as i64
+}
+
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    let mut backoff = AcceptBackoff::new();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                backoff.reset();
+                let metrics = metrics.clone();
+                // O prazo vale para a requisição inteira, não só para a primeira leitura.
+                tokio::spawn(async move {
+                    match time::timeout(REQUEST_TIMEOUT, handle_request(stream, &metrics)).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            debug!(error = %e, "Erro ao responder requisição de métricas")
+                        }
+                        Err(_) => debug!("Tempo limite excedido na requisição de métricas"),
+                    }
+                });
+            }
+            Err(e) => backoff.wait(&e, "metrics").await,
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> Result<(), Error> {
+    let request = handshake::read_handshake(&mut stream, REQUEST_MAX_LEN, REQUEST_TIMEOUT).await?;
+    let response = match request.path.as_deref() {
+        Some("/metrics") => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,455 @@
+use crate::config::{invalid, QuotaConfig, QuotaPeriod, UserConfig};
+use crate::limits::limit_key;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{self, Duration};
+use tracing::{error, info};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+const USER_PREFIX: &str = "user:";
+
+// Consumo do período corrente por chave (IP, prefixo /64 no IPv6 ou user:<nome>).
+pub struct Quotas {
+    config: QuotaConfig,
+    ip_limits: HashMap<IpAddr, u64>,
+    usage: Mutex<Usage>,
+}
+
+struct Usage {
+    period: String,
+    counters: BTreeMap<String, Arc<AtomicU64>>,
+    saved: BTreeMap<String, u64>,
+}
+
+// Formato do arquivo em quota.store.
+#[derive(Serialize, Deserialize)]
+struct Store {
+    period: String,
+    usage: BTreeMap<String, u64>,
+}
+
+// Cota aplicada a uma sessão; várias sessões da mesma chave dividem o contador.
+pub struct QuotaCounter {
+    pub key: String,
+    used: Arc<AtomicU64>,
+    limit: u64,
+}
+
+impl Quotas {
+    pub fn load(config: &QuotaConfig) -> Result<Quotas, Error> {
+        let ip_limits = config
+            .ips
+            .iter()
+            .map(|(ip, gib)| (limit_key(*ip), gib_to_bytes(*gib)))
+            .collect();
+        let period = period_start(config);
+        let mut saved = BTreeMap::new();
+        if let Some(store) = read_store(config)? {
+            if store.period == period {
+                saved = store.usage;
+            } else {
+                info!(period = %store.period, "Período de cota encerrado; consumo zerado");
+            }
+        }
+        let counters = saved
+            .iter()
+            .map(|(key, used)| (key.clone(), Arc::new(AtomicU64::new(*used))))
+            .collect();
+        Ok(Quotas {
+            config: config.clone(),
+            ip_limits,
+            usage: Mutex::new(Usage {
+                period,
+                counters,
+                saved,
+            }),
+        })
+    }
+
+    // None quando nenhuma cota se aplica ao IP.
+    pub fn for_ip(&self, ip: IpAddr) -> Option<QuotaCounter> {
+        let ip = limit_key(ip);
+        let limit = match self.ip_limits.get(&ip) {
+            Some(limit) => *limit,
+            None => gib_to_bytes(self.config.limit_gib?),
+        };
+        let key = ip_key(ip);
+        let used = self
+            .usage
+            .lock()
+            .unwrap()
+            .counters
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        Some(QuotaCounter { key, used, limit })
+    }
+
+    // Cota de um usuário autenticado com quota_gib, contada à parte da cota do IP.
+    pub fn for_user(&self, name: &str, gib: f64) -> QuotaCounter {
+        let key = user_key(name);
+        let used = self
+            .usage
+            .lock()
+            .unwrap()
+            .counters
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        QuotaCounter {
+            key,
+            used,
+            limit: gib_to_bytes(gib),
+        }
+    }
+
+    // Grava o consumo a cada quota.flush_secs e zera os contadores na virada do período.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = time::interval(Duration::from_secs(self.config.flush_secs));
+        loop {
+            interval.tick().await;
+            self.roll_period();
+            if let Err(e) = self.save() {
+                error!(error = %e, path = %self.config.store.display(), "Erro ao gravar o consumo de cotas");
+            }
+        }
+    }
+
+    fn roll_period(&self) {
+        let period = period_start(&self.config);
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period == period {
+            return;
+        }
+        info!(%period, "Novo período de cota; consumo zerado");
+        for counter in usage.counters.values() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        usage.counters.retain(|_, used| Arc::strong_count(used) > 1);
+        usage.period = period;
+    }
+
+    // Só escreve quando algo mudou desde a última gravação.
+    pub fn save(&self) -> Result<(), Error> {
+        let store = {
+            let mut usage = self.usage.lock().unwrap();
+            // Chaves sem consumo e sem sessão aberta (scanners, conexões vazias) não
+            // precisam continuar na memória. As sessões clonam o contador sob este lock.
+            usage
+                .counters
+                .retain(|_, used| Arc::strong_count(used) > 1 || used.load(Ordering::Relaxed) > 0);
+            let current: BTreeMap<String, u64> = usage
+                .counters
+                .iter()
+                .map(|(key, used)| (key.clone(), used.load(Ordering::Relaxed)))
+                .filter(|(_, used)| *used > 0)
+                .collect();
+            if current == usage.saved {
+                return Ok(());
+            }
+            Store {
+                period: usage.period.clone(),
+                usage: current,
+            }
+        };
+        write_store(&self.config, &store)?;
+        self.usage.lock().unwrap().saved = store.usage;
+        Ok(())
+    }
+}
+
+impl QuotaCounter {
+    pub fn exhausted(&self) -> bool {
+        self.used.load(Ordering::Relaxed) >= self.limit
+    }
+
+    // Retorna true quando estes bytes esgotam a cota.
+    pub fn add(&self, n: u64) -> bool {
+        self.used.fetch_add(n, Ordering::Relaxed) + n >= self.limit
+    }
+}
+
+// --quota-usage: mostra o consumo gravado em quota.store.
+pub fn print_usage(config: &QuotaConfig, users: &[UserConfig]) -> Result<(), Error> {
+    let period = period_start(config);
+    let usage = match read_store(config)? {
+        Some(store) if store.period == period => store.usage,
+        _ => BTreeMap::new(),
+    };
+    let limits: HashMap<String, f64> = config
+        .ips
+        .iter()
+        .map(|(ip, gib)| (ip_key(limit_key(*ip)), *gib))
+        .chain(
+            users
+                .iter()
+                .filter_map(|user| Some((user_key(&user.name), user.quota_gib?))),
+        )
+        .collect();
+
+    println!(
+        "Período {} desde {} (UTC); gravado a cada {}s",
+        config.period.as_str(),
+        period,
+        config.flush_secs
+    );
+    println!(
+        "{:<40} {:>12} {:>10} {:>7}",
+        "CHAVE", "USADO (GiB)", "COTA (GiB)", "USO"
+    );
+    let mut keys: Vec<&String> = usage.keys().chain(limits.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let used = usage.get(key).copied().unwrap_or(0);
+        let limit = match limits.get(key) {
+            Some(limit) => Some(*limit),
+            None if key.starts_with(USER_PREFIX) => None,
+            None => config.limit_gib,
+        };
+        let used_gib = used as f64 / GIB as f64;
+        match limit {
+            Some(limit) if limit > 0.0 => println!(
+                "{:<40} {:>12.3} {:>10} {:>6.1}%",
+                key,
+                used_gib,
+                limit,
+                used_gib / limit * 100.0
+            ),
+            Some(limit) => println!("{:<40} {:>12.3} {:>10} {:>7}", key, used_gib, limit, "-"),
+            None => println!("{:<40} {:>12.3} {:>10} {:>7}", key, used_gib, "-", "-"),
+        }
+    }
+    Ok(())
+}
+
+fn gib_to_bytes(gib: f64) -> u64 {
+    (gib * GIB as f64) as u64
+}
+
+fn user_key(name: &str) -> String {
+    format!("{}{}", USER_PREFIX, name)
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("{}/64", ip),
+    }
+}
+
+fn read_store(config: &QuotaConfig) -> Result<Option<Store>, Error> {
+    let content = match fs::read_to_string(&config.store) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(Error::new(
+                e.kind(),
+                format!("não foi possível ler {}: {}", config.store.display(), e),
+            ))
+        }
+    };
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|e| invalid(format!("{} inválido: {}", config.store.display(), e)))
+}
+
+// Grava em um arquivo temporário e renomeia, para nunca deixar o JSON pela metade.
+fn write_store(config: &QuotaConfig, store: &Store) -> Result<(), Error> {
+    let tmp = config.store.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(store)?)?;
+    fs::rename(&tmp, &config.store)
+}
+
+// Data (UTC, AAAA-MM-DD) em que começou o período corrente.
+fn period_start(config: &QuotaConfig) -> String {
+    let today = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    period_start_on(config, today as i64)
+}
+
+// Mesmo cálculo para um dia qualquer (dias desde 1970-01-01).
+fn period_start_on(config: &QuotaConfig, today: i64) -> String {
+    let start = match config.period {
+        QuotaPeriod::Daily => today,
+        QuotaPeriod::Weekly => {
+            // 1970-01-01 foi uma quinta-feira; segunda-feira = 1.
+            let weekday = (today + 3).rem_euclid(7) + 1;
+            today - (weekday - config.reset_day as i64).rem_euclid(7)
+        }
+        QuotaPeriod::Monthly => {
+            let (year, month, day) = civil_from_days(today);
+            let (year, month) = if day >= config.reset_day {
+                (year, month)
+            } else if month == 1 {
+                (year - 1, 12)
+            } else {
+                (year, month - 1)
+            };
+            days_from_civil(year, month, config.reset_day)
+        }
+    };
+    let (year, month, day) = civil_from_days(start);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Conversões entre dias desde 1970-01-01 e datas do calendário gregoriano
+// (algoritmos de Howard Hinnant).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cada configuração ganha um store próprio, já que os testes rodam em paralelo.
+    fn config(extra: &str) -> QuotaConfig {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let store = std::env::temp_dir().join(format!(
+            "rustyproxy-quota-{}-{}.json",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        toml::from_str(&format!("store = {:?}\n{}", store, extra)).unwrap()
+    }
+
+    fn day(date: &str) -> i64 {
+        let mut parts = date.split('-').map(|part| part.parse::<i64>().unwrap());
+        let (year, month, day) = (
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+        );
+        days_from_civil(year, month as u32, day as u32)
+    }
+
+    fn start(extra: &str, date: &str) -> String {
+        period_start_on(&config(extra), day(date))
+    }
+
+    #[test]
+    fn civil_date_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(
+            days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28),
+            2
+        );
+        assert_eq!(
+            days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28),
+            1
+        );
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn daily_period() {
+        assert_eq!(start("period = \"daily\"", "2026-10-18"), "2026-10-18");
+    }
+
+    #[test]
+    fn monthly_period_around_reset_day() {
+        assert_eq!(start("", "2026-10-01"), "2026-10-01");
+        assert_eq!(start("", "2026-10-31"), "2026-10-01");
+        assert_eq!(start("reset_day = 15", "2026-10-14"), "2026-09-15");
+        assert_eq!(start("reset_day = 15", "2026-10-15"), "2026-10-15");
+        assert_eq!(start("reset_day = 28", "2026-03-01"), "2026-02-28");
+    }
+
+    #[test]
+    fn monthly_period_around_year_boundary() {
+        assert_eq!(start("reset_day = 5", "2027-01-04"), "2026-12-05");
+        assert_eq!(start("reset_day = 5", "2027-01-05"), "2027-01-05");
+        assert_eq!(start("", "2026-12-31"), "2026-12-01");
+        assert_eq!(start("", "2027-01-01"), "2027-01-01");
+    }
+
+    #[test]
+    fn weekly_period() {
+        // 2026-10-18 é um domingo.
+        let weekly = "period = \"weekly\"";
+        assert_eq!(start(weekly, "2026-10-18"), "2026-10-12");
+        assert_eq!(start(weekly, "2026-10-19"), "2026-10-19");
+        let sunday = "period = \"weekly\"\nreset_day = 7";
+        assert_eq!(start(sunday, "2026-10-18"), "2026-10-18");
+        assert_eq!(start(sunday, "2026-10-17"), "2026-10-11");
+        // Semanas que atravessam o mês e o ano.
+        assert_eq!(start(weekly, "2026-11-01"), "2026-10-26");
+        assert_eq!(start(weekly, "2027-01-01"), "2026-12-28");
+        let friday = "period = \"weekly\"\nreset_day = 5";
+        assert_eq!(start(friday, "2027-01-01"), "2027-01-01");
+        assert_eq!(start(friday, "2026-12-31"), "2026-12-25");
+    }
+
+    #[test]
+    fn ipv6_keys_use_the_prefix() {
+        let quotas = Quotas::load(&config("limit_gib = 1\n[ips]\n\"10.0.0.9\" = 2")).unwrap();
+        let counter = quotas.for_ip("2001:db8::1".parse().unwrap()).unwrap();
+        assert_eq!(counter.key, "2001:db8::/64");
+        assert_eq!(counter.limit, GIB);
+        let counter = quotas.for_ip("10.0.0.9".parse().unwrap()).unwrap();
+        assert_eq!(counter.limit, 2 * GIB);
+    }
+
+    #[test]
+    fn counters_are_shared_and_pruned_when_idle() {
+        let config = config("limit_gib = 0.000001");
+        let quotas = Quotas::load(&config).unwrap();
+        let ip = "203.0.113.7".parse().unwrap();
+        let first = quotas.for_ip(ip).unwrap();
+        let second = quotas.for_ip(ip).unwrap();
+        assert!(!first.add(1000));
+        assert!(second.add(100));
+        assert!(first.exhausted());
+
+        let scanner = quotas.for_ip("198.51.100.1".parse().unwrap()).unwrap();
+        drop(scanner);
+        quotas.save().unwrap();
+        let keys: Vec<String> = quotas
+            .usage
+            .lock()
+            .unwrap()
+            .counters
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(keys, ["203.0.113.7"]);
+
+        // O consumo gravado volta ao carregar de novo.
+        let reloaded = Quotas::load(&config).unwrap();
+        assert!(reloaded.for_ip(ip).unwrap().exhausted());
+        fs::remove_file(&config.store).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::buffer::AdaptiveBuffer;
+use crate::config::Config;
+use crate::session::{CloseReason, Direction, Session};
+#[cfg(target_os = "linux")]
+use crate::splice;
+use crate::stream::ClientStream;
+use std::future::Future;
+use std::io::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, info};
+
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub async fn relay(
+    client_stream: ClientStream,
+    server_stream: TcpStream,
+    session: &Session,
+    config: &Config,
+) -> Result<CloseReason, Error> {
+    // splice só funciona entre descritores do kernel, então TLS terminado fica de fora.
+    #[cfg(target_os = "linux")]
+    let client_stream = match client_stream {
+        ClientStream::Tcp(client) if config.splice => {
+            let pipes = splice::Pipe::new().and_then(|up| {
+                let down = splice::Pipe::new()?;
+                splice::probe(&up, &server_stream)?;
+                splice::probe(&down, &client)?;
+                Ok([up, down])
+            });
+            match pipes {
+                Ok(pipes) => {
+                    return relay_splice(client, server_stream, pipes, session, config).await
+                }
+                Err(e) => {
+                    debug!(error = %e, "splice indisponível; usando cópia em buffer");
+                    ClientStream::Tcp(client)
+                }
+            }
+        }
+        client_stream => client_stream,
+    };
+
+    let (mut client_read, mut client_write) = tokio::io::split(client_stream);
+    let (mut server_read, mut server_write) = server_stream.into_split();
+
+    let client_to_server = transfer_data(
+        &mut client_read,
+        &mut server_write,
+        config.buffer_size,
+        session,
+        Direction::Up,
+    );
+    let server_to_client = transfer_data(
+        &mut server_read,
+        &mut client_write,
+        config.buffer_size,
+        session,
+        Direction::Down,
+    );
+    let result = drive(client_to_server, server_to_client, session, config).await;
+
+    // Garante o FIN nos dois lados também após linger, reset ou tempo limite;
+    // nas metades já encerradas isso não tem efeito.
+    let _ = timeout(SHUTDOWN_TIMEOUT, async {
+        tokio::join!(client_write.shutdown(), server_write.shutdown())
+    })
+    .await;
+    result
+}
+
+#[cfg(target_os = "linux")]
+async fn relay_splice(
+    client_stream: TcpStream,
+    server_stream: TcpStream,
+    [up, down]: [splice::Pipe; 2],
+    session: &Session,
+    config: &Config,
+) -> Result<CloseReason, Error> {
+    let client_to_server =
+        splice::transfer(&client_stream, &server_stream, &up, session, Direction::Up);
+    let server_to_client = splice::transfer(
+        &server_stream,
+        &client_stream,
+        &down,
+        session,
+        Direction::Down,
+    );
+    let result = drive(client_to_server, server_to_client, session, config).await;
+
+    let _ = splice::shutdown_write(&client_stream);
+    let _ = splice::shutdown_write(&server_stream);
+    result
+}
+
+// Quando um sentido chega ao fim, o FIN é repassado e o outro sentido tem até
+// timeouts.linger_secs para terminar. Um erro em um sentido encerra os dois.
+pub async fn drive<U, D>(
+    client_to_server: U,
+    server_to_client: D,
+    session: &Session,
+    config: &Config,
+) -> Result<CloseReason, Error>
+where
+    U: Future<Output = Result<(), Error>>,
+    D: Future<Output = Result<(), Error>>,
+{
+    let linger = Duration::from_secs(config.timeouts.linger_secs);
+
+    let relay = async {
+        tokio::pin!(client_to_server, server_to_client);
+
+        let (first, direction) = tokio::select! {
+            result = &mut client_to_server => (result, Direction::Up),
+            result = &mut server_to_client => (result, Direction::Down),
+        };
+        first.map_err(|e| (direction, e))?;
+        debug!(
+            ?direction,
+            "Fim de stream repassado; aguardando o outro sentido"
+        );
+
+        let (rest, direction) = match direction {
+            Direction::Up => (
+                timeout(linger, &mut server_to_client).await,
+                Direction::Down,
+            ),
+            Direction::Down => (timeout(linger, &mut client_to_server).await, Direction::Up),
+        };
+        match rest {
+            Ok(result) => result.map_err(|e| (direction, e)),
+            Err(_) => {
+                debug!(?direction, "Tempo de linger esgotado");
+                Ok(())
+            }
+        }
+    };
+
+    tokio::select! {
+        result = relay => match result {
+            Ok(()) => Ok(CloseReason::Eof),
+            Err((direction, e)) => match CloseReason::from_error(&e) {
+                CloseReason::Reset => {
+                    info!(?direction, error = %e, "Conexão reiniciada");
+                    Ok(CloseReason::Reset)
+                }
+                _ => Err(e),
+            },
+        },
+        reason = session.expired(&config.timeouts) => Ok(reason),
+    }
+}
+
+async fn transfer_data<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut read_stream: R,
+    mut write_stream: W,
+    buffer_size: usize,
+    session: &Session,
+    direction: Direction,
+) -> Result<(), Error> {
+    let mut buffer = AdaptiveBuffer::new(buffer_size, session);
+    loop {
+        let bytes_read = buffer
+            .read_from(&mut read_stream, |len| session.chunk_len(direction, len))
+            .await?;
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        session.throttle(direction, bytes_read).await;
+        write_stream
+            .write_all(&buffer.as_mut()[..bytes_read])
+            .await?;
+        session.add_bytes(direction, bytes_read);
+        buffer.record(bytes_read);
+    }
+
+    write_stream.shutdown().await
+}
@@ -0,0 +1,375 @@
+use crate::config::{Config, ListenerConfig, RuleConfig};
+use crate::sniff::Protocol;
+use regex::bytes::Regex;
+use serde::{Deserialize, Deserializer};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Tudo o que as regras podem inspecionar sobre uma conexão.
+pub struct RouteInput<'a> {
+    pub data: &'a [u8],
+    pub protocol: Protocol,
+    pub host: Option<&'a str>,
+    pub path: Option<&'a str>,
+    pub sni: Option<&'a str>,
+    pub alpn: &'a [String],
+    pub peer: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Debug)]
+pub struct Pattern(Regex);
+
+#[derive(Debug)]
+pub struct HexBytes(Vec<u8>);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+// Regras com forward_http são avaliadas antes de responder ao cliente, para que a
+// requisição HTTP original siga intacta até o backend (ex.: painel web).
+pub fn select_forward<'c>(
+    config: &'c Config,
+    listener: &'c ListenerConfig,
+    input: &RouteInput,
+) -> Option<&'c str> {
+    config
+        .rules_for(listener)
+        .iter()
+        .find(|rule| rule.forward_http && rule.matches(input))
+        .map(|rule| rule.backend.as_str())
+}
+
+pub fn select_backend<'c>(
+    config: &'c Config,
+    listener: &'c ListenerConfig,
+    input: &RouteInput,
+) -> Option<&'c str> {
+    config
+        .rules_for(listener)
+        .iter()
+        .find(|rule| !rule.forward_http && rule.matches(input))
+        .map(|rule| rule.backend.as_str())
+        .or_else(|| config.default_backend_for(listener))
+}
+
+impl RuleConfig {
+    pub fn has_condition(&self) -> bool {
+        self.protocol.is_some()
+            || self.contains.is_some()
+            || self.prefix.is_some()
+            || self.prefix_hex.is_some()
+            || self.regex.is_some()
+            || self.host.is_some()
+            || self.path.is_some()
+            || self.sni.is_some()
+            || self.alpn.is_some()
+            || self.source.is_some()
+            || self.port.is_some()
+            || self.empty
+    }
+
+    fn matches(&self, input: &RouteInput) -> bool {
+        let data = input.data;
+        self.protocol
+            .is_none_or(|protocol| protocol == input.protocol)
+            && self
+                .contains
+                .as_ref()
+                .is_none_or(|keyword| contains(data, keyword.as_bytes()))
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| data.starts_with(prefix.as_bytes()))
+            && self
+                .prefix_hex
+                .as_ref()
+                .is_none_or(|prefix| data.starts_with(&prefix.0))
+            && self
+                .regex
+                .as_ref()
+                .is_none_or(|pattern| pattern.0.is_match(data))
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|host| input.host.is_some_and(|value| host_matches(host, value)))
+            && self.path.as_ref().is_none_or(|path| {
+                input
+                    .path
+                    .is_some_and(|value| value.starts_with(path.as_str()))
+            })
+            && self
+                .sni
+                .as_ref()
+                .is_none_or(|sni| input.sni.is_some_and(|value| host_matches(sni, value)))
+            && self
+                .alpn
+                .as_ref()
+                .is_none_or(|alpn| input.alpn.iter().any(|value| value == alpn))
+            && self.source.is_none_or(|cidr| cidr.contains(input.peer))
+            && self.port.is_none_or(|port| port == input.port)
+            && (!self.empty || data.is_empty())
+    }
+}
+
+// "*.exemplo.com" casa com qualquer subdomínio; demais nomes comparam sem caixa.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .to_ascii_lowercase()
+            .strip_suffix(&suffix.to_ascii_lowercase())
+            .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("endereço inválido em '{}'", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("prefixo inválido em '{}'", s))?,
+            None => max_len,
+        };
+        // Os peers são comparados na forma canônica, então "::ffff:10.0.0.0/104" vira 10.0.0.0/8.
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix_len >= 96 => Ok(Cidr {
+                addr: IpAddr::V4(v4),
+                prefix_len: prefix_len - 96,
+            }),
+            _ => Ok(Cidr { addr, prefix_len }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Regex::new(&value)
+            .map(Pattern)
+            .map_err(|e| serde::de::Error::custom(format!("regex inválida: {}", e)))
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let digits: Vec<u8> = value.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        // from_str_radix aceitaria sinal ("+1"), então os dígitos são conferidos antes.
+        if digits.is_empty()
+            || !digits.len().is_multiple_of(2)
+            || !digits.iter().all(u8::is_ascii_hexdigit)
+        {
+            return Err(serde::de::Error::custom(format!(
+                "hexadecimal inválido: '{}'",
+                value
+            )));
+        }
+        digits
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .map(HexBytes)
+            .ok_or_else(|| serde::de::Error::custom(format!("hexadecimal inválido: '{}'", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn cidr(value: &str) -> Cidr {
+        value.parse().unwrap()
+    }
+
+    fn input<'a>(data: &'a [u8], protocol: Protocol) -> RouteInput<'a> {
+        RouteInput {
+            data,
+            protocol,
+            host: None,
+            path: None,
+            sni: None,
+            alpn: &[],
+            peer: ip("192.0.2.1"),
+            port: 80,
+        }
+    }
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(&format!(
+            "{}\n[[listeners]]\nport = 80\n[backends]\nssh = \"127.0.0.1:22\"\nweb = \"127.0.0.1:8080\"\nvpn = \"127.0.0.1:1194\"\n",
+            toml
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("exemplo/8".parse::<Cidr>().is_err());
+        assert!(cidr("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(cidr("2001:db8::1").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn cidr_prefixes() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn cidr_ipv4_mapped() {
+        // Peers IPv4 aceitos em socket IPv6 chegam como ::ffff:a.b.c.d.
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("::ffff:10.0.0.0/104").contains(ip("11.1.2.3")));
+        assert!(!cidr("::/0").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn host_wildcards() {
+        assert!(host_matches("exemplo.com", "EXEMPLO.com"));
+        assert!(!host_matches("exemplo.com", "www.exemplo.com"));
+        assert!(host_matches("*.exemplo.com", "www.exemplo.com"));
+        assert!(host_matches("*.Exemplo.com", "a.b.EXEMPLO.COM"));
+        assert!(!host_matches("*.exemplo.com", "exemplo.com"));
+        assert!(!host_matches("*.exemplo.com", ".exemplo.com"));
+        assert!(!host_matches("*.exemplo.com", "outroexemplo.com"));
+    }
+
+    #[test]
+    fn hex_bytes() {
+        let parse = |value: &str| {
+            toml::from_str::<RuleConfig>(&format!("backend = \"x\"\nprefix_hex = \"{}\"", value))
+        };
+        assert_eq!(
+            parse("16 03 01").unwrap().prefix_hex.unwrap().0,
+            [0x16, 0x03, 0x01]
+        );
+        assert_eq!(parse("abCD").unwrap().prefix_hex.unwrap().0, [0xab, 0xcd]);
+        assert!(parse("").is_err());
+        assert!(parse("abc").is_err());
+        assert!(parse("zz").is_err());
+        assert!(parse("+1").is_err());
+    }
+
+    #[test]
+    fn rules_in_order() {
+        let config = config(
+            "default_backend = \"vpn\"\n\
+             [[rules]]\nbackend = \"web\"\nprotocol = \"http\"\npath = \"/painel\"\nforward_http = true\n\
+             [[rules]]\nbackend = \"ssh\"\ncontains = \"SSH\"\n\
+             [[rules]]\nbackend = \"web\"\nprefix = \"SSH-2.0-web\"\n\
+             [[rules]]\nbackend = \"web\"\nsource = \"10.0.0.0/8\"\nempty = true\n",
+        );
+        let listener = &config.listeners[0];
+
+        // A primeira regra que casa vence, mesmo que uma posterior seja mais específica.
+        let data = input(b"SSH-2.0-web", Protocol::Ssh);
+        assert_eq!(select_backend(&config, listener, &data), Some("ssh"));
+        assert_eq!(select_forward(&config, listener, &data), None);
+
+        let mut data = input(b"", Protocol::Unknown);
+        assert_eq!(select_backend(&config, listener, &data), Some("vpn"));
+        data.peer = ip("::ffff:10.0.0.7");
+        assert_eq!(select_backend(&config, listener, &data), Some("web"));
+
+        // forward_http só vale antes da resposta; select_backend ignora a regra.
+        let mut data = input(b"GET /painel HTTP/1.1\r\n\r\n", Protocol::Http);
+        data.path = Some("/painel/login");
+        assert_eq!(select_forward(&config, listener, &data), Some("web"));
+        assert_eq!(select_backend(&config, listener, &data), Some("vpn"));
+        data.path = Some("/outro");
+        assert_eq!(select_forward(&config, listener, &data), None);
+    }
+
+    #[test]
+    fn host_sni_and_port_conditions() {
+        let config = config(
+            "[[rules]]\nbackend = \"web\"\nhost = \"*.exemplo.com\"\n\
+             [[rules]]\nbackend = \"vpn\"\nsni = \"vpn.exemplo.com\"\nalpn = \"h2\"\n\
+             [[rules]]\nbackend = \"ssh\"\nport = 443\n",
+        );
+        let listener = &config.listeners[0];
+        let alpn = ["http/1.1".to_string(), "h2".to_string()];
+
+        let mut data = input(b"", Protocol::Tls);
+        assert_eq!(select_backend(&config, listener, &data), None);
+        data.host = Some("painel.exemplo.com");
+        assert_eq!(select_backend(&config, listener, &data), Some("web"));
+        data.host = None;
+        data.sni = Some("VPN.exemplo.com");
+        assert_eq!(select_backend(&config, listener, &data), None);
+        data.alpn = &alpn;
+        assert_eq!(select_backend(&config, listener, &data), Some("vpn"));
+        data.sni = None;
+        data.port = 443;
+        assert_eq!(select_backend(&config, listener, &data), Some("ssh"));
+    }
+}
@@ -0,0 +1,337 @@
+use crate::bandwidth::Shaper;
+use crate::config::{RateConfig, TimeoutsConfig};
+use crate::limits::ConnectionLimits;
+use crate::metrics::{ListenerMetrics, Metrics, RouteMetrics};
+use crate::quota::{QuotaCounter, Quotas};
+use crate::sniff::Protocol;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+use tokio::time::{self, Duration, Instant};
+
+// Estado de uma conexão compartilhado entre o relay e quem precisa observá-la.
+pub struct Session {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub port: u16,
+    pub started: Instant,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    // Bytes em buffers de relay mantidos pela sessão neste momento.
+    pub buffer_bytes: AtomicI64,
+    // Milissegundos desde `started` até o último byte repassado.
+    last_activity_ms: AtomicU64,
+    pub metrics: Arc<ListenerMetrics>,
+    registry: Arc<Metrics>,
+    route: OnceLock<Arc<RouteMetrics>>,
+    backend: OnceLock<String>,
+    protocol: OnceLock<Protocol>,
+    shaper: Shaper,
+    user: OnceLock<String>,
+    quotas: Option<Arc<Quotas>>,
+    ip_quota: Option<QuotaCounter>,
+    user_quota: OnceLock<QuotaCounter>,
+    quota_exhausted: Notify,
+    kill: Notify,
+}
+
+// Sessões em andamento, para listagem e encerramento pela API de administração.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // Do cliente para o backend.
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Eof,
+    Reset,
+    Timeout,
+    HandshakeTimeout,
+    Idle,
+    MaxLifetime,
+    BackendRefused,
+    NoRoute,
+    TlsError,
+    InvalidRequest,
+    Killed,
+    QuotaExceeded,
+    AuthFailed,
+    Error,
+}
+
+impl Session {
+    pub fn new(
+        id: u64,
+        peer: SocketAddr,
+        port: u16,
+        registry: Arc<Metrics>,
+        limits: &ConnectionLimits,
+        listener_index: usize,
+    ) -> Self {
+        let metrics = registry.listener(port);
+        metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        metrics.active.fetch_add(1, Ordering::Relaxed);
+        Session {
+            id,
+            peer,
+            port,
+            started: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            buffer_bytes: AtomicI64::new(0),
+            last_activity_ms: AtomicU64::new(0),
+            metrics,
+            registry,
+            route: OnceLock::new(),
+            backend: OnceLock::new(),
+            protocol: OnceLock::new(),
+            shaper: limits.bandwidth.shaper(listener_index, peer.ip()),
+            user: OnceLock::new(),
+            quotas: limits.quotas.clone(),
+            ip_quota: limits
+                .quotas
+                .as_ref()
+                .and_then(|quotas| quotas.for_ip(peer.ip())),
+            user_quota: OnceLock::new(),
+            quota_exhausted: Notify::new(),
+            kill: Notify::new(),
+        }
+    }
+
+    pub fn set_backend(&self, backend: &str) {
+        if self.backend.set(backend.to_string()).is_ok() {
+            let route = self.registry.route(self.port, backend);
+            route.active.fetch_add(1, Ordering::Relaxed);
+            let _ = self.route.set(route);
+        }
+    }
+
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.get().map(String::as_str)
+    }
+
+    pub fn set_protocol(&self, protocol: Protocol) {
+        let _ = self.protocol.set(protocol);
+    }
+
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.protocol.get().copied()
+    }
+
+    pub fn kill(&self) {
+        // notify_one guarda a notificação caso a tarefa ainda não esteja esperando.
+        self.kill.notify_one();
+    }
+
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    pub fn route(&self) -> Option<&RouteMetrics> {
+        self.route.get().map(Arc::as_ref)
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed))
+    }
+
+    // Usuário autenticado, com seu limite de banda e sua cota, se houver.
+    pub fn set_user(&self, name: &str, bandwidth: Option<&RateConfig>, quota_gib: Option<f64>) {
+        let _ = self.user.set(name.to_string());
+        if let Some(rate) = bandwidth {
+            self.shaper.set_user(name, rate);
+        }
+        if let (Some(quotas), Some(gib)) = (&self.quotas, quota_gib) {
+            let _ = self.user_quota.set(quotas.for_user(name, gib));
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.get().map(String::as_str)
+    }
+
+    // Primeira cota esgotada entre a do IP e a do usuário.
+    pub fn exhausted_quota(&self) -> Option<&QuotaCounter> {
+        self.quotas().find(|quota| quota.exhausted())
+    }
+
+    fn quotas(&self) -> impl Iterator<Item = &QuotaCounter> {
+        self.ip_quota.iter().chain(self.user_quota.get())
+    }
+
+    // Resolve quando a sessão passa de timeouts.idle_secs sem tráfego, de
+    // timeouts.max_session_secs ou esgota a cota de tráfego.
+    pub async fn expired(&self, timeouts: &TimeoutsConfig) -> CloseReason {
+        tokio::select! {
+            reason = self.timed_out(timeouts) => reason,
+            _ = self.quota_exhausted.notified() => CloseReason::QuotaExceeded,
+        }
+    }
+
+    async fn timed_out(&self, timeouts: &TimeoutsConfig) -> CloseReason {
+        let idle = timeouts.idle_secs.map(Duration::from_secs);
+        let lifetime_end = timeouts
+            .max_session_secs
+            .map(|secs| self.started + Duration::from_secs(secs));
+        loop {
+            let idle_end = idle.map(|idle| self.last_activity() + idle);
+            let next = match (idle_end, lifetime_end) {
+                (Some(idle_end), Some(lifetime_end)) => idle_end.min(lifetime_end),
+                (Some(end), None) | (None, Some(end)) => end,
+                (None, None) => return std::future::pending().await,
+            };
+            time::sleep_until(next).await;
+
+            let now = Instant::now();
+            if lifetime_end.is_some_and(|end| now >= end) {
+                return CloseReason::MaxLifetime;
+            }
+            if idle.is_some_and(|idle| now >= self.last_activity() + idle) {
+                return CloseReason::Idle;
+            }
+        }
+    }
+
+    pub fn charge_buffer(&self, delta: i64) {
+        self.buffer_bytes.fetch_add(delta, Ordering::Relaxed);
+        self.metrics
+            .buffer_bytes
+            .fetch_add(delta, Ordering::Relaxed);
+    }
+
+    // Tamanho da próxima leitura respeitando a rajada dos limites de banda.
+    pub fn chunk_len(&self, direction: Direction, len: usize) -> usize {
+        self.shaper.chunk_len(direction, len)
+    }
+
+    // Aplica os limites de banda a `n` bytes já lidos, antes de repassá-los.
+    pub async fn throttle(&self, direction: Direction, n: usize) {
+        if self.shaper.throttle(direction, n).await {
+            self.metrics
+                .bandwidth_delays
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_bytes(&self, direction: Direction, n: usize) {
+        self.last_activity_ms
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        let n = n as u64;
+        for quota in self.quotas() {
+            if quota.add(n) {
+                self.quota_exhausted.notify_one();
+            }
+        }
+        let route = self.route();
+        match direction {
+            Direction::Up => {
+                self.bytes_up.fetch_add(n, Ordering::Relaxed);
+                if let Some(route) = route {
+                    route.bytes_up.fetch_add(n, Ordering::Relaxed);
+                }
+            }
+            Direction::Down => {
+                self.bytes_down.fetch_add(n, Ordering::Relaxed);
+                if let Some(route) = route {
+                    route.bytes_down.fetch_add(n, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+impl Sessions {
+    pub fn insert(&self, session: Arc<Session>) {
+        self.sessions.lock().unwrap().insert(session.id, session);
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn kill_peer(&self, ip: IpAddr) -> usize {
+        let ip = ip.to_canonical();
+        let sessions = self.sessions.lock().unwrap();
+        let mut killed = 0;
+        for session in sessions.values() {
+            if session.peer.ip().to_canonical() == ip {
+                session.kill();
+                killed += 1;
+            }
+        }
+        killed
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.metrics.active.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.duration.observe(self.started.elapsed());
+        if let Some(route) = self.route() {
+            route.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl CloseReason {
+    pub fn from_error(e: &Error) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                CloseReason::Reset
+            }
+            ErrorKind::TimedOut => CloseReason::Timeout,
+            ErrorKind::InvalidData => CloseReason::InvalidRequest,
+            _ => CloseReason::Error,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Eof => "eof",
+            CloseReason::Reset => "reset",
+            CloseReason::Timeout => "timeout",
+            CloseReason::HandshakeTimeout => "handshake_timeout",
+            CloseReason::Idle => "idle_timeout",
+            CloseReason::MaxLifetime => "max_lifetime",
+            CloseReason::BackendRefused => "backend_refused",
+            CloseReason::NoRoute => "no_route",
+            CloseReason::TlsError => "tls_error",
+            CloseReason::InvalidRequest => "invalid_request",
+            CloseReason::Killed => "killed",
+            CloseReason::QuotaExceeded => "quota_exceeded",
+            CloseReason::AuthFailed => "auth_failed",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
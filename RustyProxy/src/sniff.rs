@@ -0,0 +1,315 @@
+use serde::Deserialize;
+
+const MAX_METHOD_LEN: usize = 16;
+
+// OpenVPN sobre TCP: 2 bytes de tamanho e o opcode nos 5 bits altos do byte seguinte.
+const OPENVPN_HARD_RESET_CLIENT_V1: u8 = 1;
+const OPENVPN_HARD_RESET_CLIENT_V2: u8 = 7;
+const OPENVPN_HARD_RESET_CLIENT_V3: u8 = 10;
+// opcode + session id (8 bytes) + ack array length + packet id.
+const OPENVPN_MIN_RESET_LEN: usize = 14;
+const OPENVPN_MAX_RESET_LEN: usize = 1024;
+
+const TLS_RECORD_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const TLS_MAX_RECORD_LEN: usize = 16384;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+const TLS_EXT_ALPN: u16 = 0x0010;
+const TLS_SERVER_NAME_HOST: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Ssh,
+    Http,
+    #[serde(rename = "openvpn")]
+    OpenVpn,
+    Tls,
+    Unknown,
+}
+
+#[derive(Debug, Default)]
+pub struct ClientHello {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+}
+
+pub fn detect(data: &[u8]) -> Protocol {
+    if data.starts_with(b"SSH-") {
+        Protocol::Ssh
+    } else if looks_like_http(data) {
+        Protocol::Http
+    } else if looks_like_openvpn(data) {
+        Protocol::OpenVpn
+    } else if tls_record_len(data).is_some() {
+        Protocol::Tls
+    } else {
+        Protocol::Unknown
+    }
+}
+
+// Tamanho total do primeiro registro TLS de handshake, cabeçalho incluso.
+pub fn tls_record_len(data: &[u8]) -> Option<usize> {
+    if data.len() < TLS_RECORD_HEADER_LEN || data[0] != TLS_RECORD_HANDSHAKE || data[1] != 0x03 {
+        return None;
+    }
+    let len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if len == 0 || len > TLS_MAX_RECORD_LEN {
+        return None;
+    }
+    Some(TLS_RECORD_HEADER_LEN + len)
+}
+
+// Extrai SNI e ALPN do ClientHello sem decifrar nada; None se os bytes não formarem
+// um ClientHello completo no primeiro registro.
+pub fn parse_client_hello(data: &[u8]) -> Option<ClientHello> {
+    let record_len = tls_record_len(data)?;
+    let mut record = Cursor::new(data.get(TLS_RECORD_HEADER_LEN..record_len)?);
+    if record.u8()? != TLS_HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let hello_len = record.u24()?;
+    let mut hello = Cursor::new(record.take(hello_len)?);
+    hello.take(2 + 32)?; // versão + random
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compressão
+
+    let mut info = ClientHello::default();
+    let mut extensions = match hello.vec16() {
+        Some(extensions) => Cursor::new(extensions),
+        None => return Some(info),
+    };
+    while !extensions.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut ext = Cursor::new(extensions.vec16()?);
+        match ext_type {
+            TLS_EXT_SERVER_NAME => {
+                let mut names = Cursor::new(ext.vec16()?);
+                while !names.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec16()?;
+                    if name_type == TLS_SERVER_NAME_HOST {
+                        info.sni = std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+                    }
+                }
+            }
+            TLS_EXT_ALPN => {
+                let mut protocols = Cursor::new(ext.vec16()?);
+                while !protocols.is_empty() {
+                    let protocol = protocols.vec8()?;
+                    info.alpn
+                        .push(String::from_utf8_lossy(protocol).to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Cursor { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+fn looks_like_openvpn(data: &[u8]) -> bool {
+    if data.len() < 3 {
+        return false;
+    }
+    let packet_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let opcode = data[2] >> 3;
+    (OPENVPN_MIN_RESET_LEN..=OPENVPN_MAX_RESET_LEN).contains(&packet_len)
+        && matches!(
+            opcode,
+            OPENVPN_HARD_RESET_CLIENT_V1
+                | OPENVPN_HARD_RESET_CLIENT_V2
+                | OPENVPN_HARD_RESET_CLIENT_V3
+        )
+}
+
+// Um método HTTP é um token em maiúsculas seguido de espaço; "SSH-2.0-..." e
+// protocolos binários não passam nesse teste.
+pub fn looks_like_http(data: &[u8]) -> bool {
+    let method_len = data
+        .iter()
+        .take(MAX_METHOD_LEN + 1)
+        .position(|&b| b == b' ');
+    match method_len {
+        Some(len) if len > 0 => data[..len].iter().all(u8::is_ascii_uppercase),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec8(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![data.len() as u8];
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn extension(ext_type: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = ext_type.to_be_bytes().to_vec();
+        out.extend(vec16(body));
+        out
+    }
+
+    // Registro TLS com um ClientHello contendo as extensões dadas.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0x11; 32]);
+        hello.extend(vec8(&[0xAA; 32]));
+        hello.extend(vec16(&[0x13, 0x01, 0x13, 0x02]));
+        hello.extend(vec8(&[0x00]));
+        hello.extend(vec16(extensions));
+
+        let mut handshake = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![TLS_RECORD_HANDSHAKE, 0x03, 0x01];
+        record.extend(vec16(&handshake));
+        record
+    }
+
+    fn sni(host: &str) -> Vec<u8> {
+        let mut name = vec![TLS_SERVER_NAME_HOST];
+        name.extend(vec16(host.as_bytes()));
+        extension(TLS_EXT_SERVER_NAME, &vec16(&name))
+    }
+
+    fn alpn(protocols: &[&str]) -> Vec<u8> {
+        let list: Vec<u8> = protocols
+            .iter()
+            .flat_map(|protocol| vec8(protocol.as_bytes()))
+            .collect();
+        extension(TLS_EXT_ALPN, &vec16(&list))
+    }
+
+    #[test]
+    fn client_hello_with_sni_and_alpn() {
+        let mut extensions = extension(0x002b, &[0x02, 0x03, 0x04]);
+        extensions.extend(sni("Exemplo.COM"));
+        extensions.extend(alpn(&["h2", "http/1.1"]));
+        let data = client_hello(&extensions);
+
+        assert_eq!(detect(&data), Protocol::Tls);
+        assert_eq!(tls_record_len(&data), Some(data.len()));
+        let hello = parse_client_hello(&data).unwrap();
+        assert_eq!(hello.sni.as_deref(), Some("exemplo.com"));
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+    }
+
+    #[test]
+    fn client_hello_without_extensions() {
+        let mut data = client_hello(&[]);
+        // Remove o bloco de extensões vazio, como em ClientHellos antigos.
+        data.truncate(data.len() - 2);
+        let len = data.len() - TLS_RECORD_HEADER_LEN;
+        data[3..5].copy_from_slice(&(len as u16).to_be_bytes());
+        data[6..9].copy_from_slice(&(len as u32 - 4).to_be_bytes()[1..]);
+
+        let hello = parse_client_hello(&data).unwrap();
+        assert_eq!(hello.sni, None);
+        assert!(hello.alpn.is_empty());
+    }
+
+    #[test]
+    fn client_hello_truncated_mid_extension() {
+        let mut extensions = sni("exemplo.com");
+        extensions.extend(alpn(&["h2"]));
+        let data = client_hello(&extensions);
+
+        // O registro anuncia mais bytes do que chegaram.
+        let cut = data.len() - 4;
+        assert_eq!(tls_record_len(&data[..cut]), Some(data.len()));
+        assert!(parse_client_hello(&data[..cut]).is_none());
+
+        // Registro completo, mas a extensão ALPN anuncia mais do que o bloco contém.
+        let mut broken = data.clone();
+        let alpn_len_at = data.len() - 2 - 2 - 1 - 2;
+        broken[alpn_len_at..alpn_len_at + 2].copy_from_slice(&40u16.to_be_bytes());
+        assert!(parse_client_hello(&broken).is_none());
+    }
+
+    #[test]
+    fn tls_record_len_rejects_other_records() {
+        assert_eq!(tls_record_len(&[0x17, 0x03, 0x03, 0x00, 0x10]), None);
+        assert_eq!(tls_record_len(&[0x16, 0x03, 0x01, 0x00, 0x00]), None);
+        assert_eq!(tls_record_len(&[0x16, 0x03, 0x01, 0x40, 0x01]), None);
+        assert_eq!(tls_record_len(&[0x16, 0x03]), None);
+    }
+
+    #[test]
+    fn detects_protocols() {
+        assert_eq!(detect(b"SSH-2.0-OpenSSH_9.6\r\n"), Protocol::Ssh);
+        assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Protocol::Http);
+        assert_eq!(detect(b"CONNECT a:443 HTTP/1.1\r\n"), Protocol::Http);
+        let mut openvpn = vec![0x00, 0x0e, OPENVPN_HARD_RESET_CLIENT_V2 << 3];
+        openvpn.extend([0; 13]);
+        assert_eq!(detect(&openvpn), Protocol::OpenVpn);
+        assert_eq!(detect(b""), Protocol::Unknown);
+        assert_eq!(detect(b"\x00\x01\x02\x03"), Protocol::Unknown);
+    }
+
+    #[test]
+    fn http_method_must_be_uppercase_token() {
+        assert!(looks_like_http(b"POST /x HTTP/1.1"));
+        assert!(!looks_like_http(b"get / HTTP/1.1"));
+        assert!(!looks_like_http(b" GET /"));
+        assert!(!looks_like_http(b"GET"));
+        assert!(!looks_like_http(b"AVERYVERYLONGMETHOD /"));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::session::{Direction, Session};
+use std::io::{Error, ErrorKind};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+// Capacidade padrão de um pipe no Linux; cada splice move no máximo isso.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+// Pipe intermediário de um sentido do relay: splice(2) só move dados quando uma
+// das pontas é um pipe.
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> Result<Pipe, Error> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: pipe2 acabou de criar os dois descritores e ninguém mais é dono deles.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Pipe { read, write })
+    }
+}
+
+// Confirma que o kernel aceita splice(2) para o socket antes de usar o relay por pipe.
+// Com o pipe vazio nada é movido: EAGAIN indica suporte, e EINVAL ou ENOSYS (kernel ou
+// sandbox sem splice) fazem o relay voltar para a cópia em buffer.
+pub fn probe(pipe: &Pipe, stream: &TcpStream) -> Result<(), Error> {
+    match splice(pipe.read.as_raw_fd(), stream.as_raw_fd(), PIPE_CAPACITY) {
+        Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Equivalente ao transfer_data do relay, sem copiar os bytes para espaço de usuário.
+pub async fn transfer(
+    from: &TcpStream,
+    to: &TcpStream,
+    pipe: &Pipe,
+    session: &Session,
+    direction: Direction,
+) -> Result<(), Error> {
+    let read_len = session.chunk_len(direction, PIPE_CAPACITY);
+    loop {
+        // O pipe está sempre vazio aqui, então EAGAIN só pode vir do socket.
+        let bytes_read = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), read_len)
+            })
+            .await?;
+        if bytes_read == 0 {
+            break;
+        }
+        session.throttle(direction, bytes_read).await;
+
+        let mut pending = bytes_read;
+        while pending > 0 {
+            let written = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)
+                })
+                .await?;
+            if written == 0 {
+                return Err(Error::from(ErrorKind::WriteZero));
+            }
+            pending -= written;
+        }
+        session.add_bytes(direction, bytes_read);
+    }
+
+    shutdown_write(to)
+}
+
+pub fn shutdown_write(stream: &TcpStream) -> Result<(), Error> {
+    if unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> Result<usize, Error> {
+    let moved = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if moved < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(moved as usize)
+}
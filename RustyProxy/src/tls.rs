@@ -0,0 +1,100 @@
+use crate::config::{invalid, TlsConfig};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+// Escolhe o certificado pelo SNI: nome exato, depois curinga "*.dominio", depois o padrão.
+#[derive(Debug)]
+struct SniResolver {
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = match client_hello.server_name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Some(self.default.clone()),
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        self.certs
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.certs.get(&wildcard)))
+            .or(Some(&self.default))
+            .cloned()
+    }
+}
+
+pub fn load_acceptor(tls: &TlsConfig) -> Result<TlsAcceptor, Error> {
+    let default = load_certified_key(&tls.cert, &tls.key)?;
+    let certs = match &tls.cert_dir {
+        Some(dir) => load_cert_dir(dir)?,
+        None => HashMap::new(),
+    };
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver { certs, default }));
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// Cada "<host>.pem" do diretório precisa de um "<host>.key" ao lado; "_.dominio" vira
+// o curinga "*.dominio".
+fn load_cert_dir(dir: &Path) -> Result<HashMap<String, Arc<CertifiedKey>>, Error> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        invalid(format!(
+            "diretório de certificados {}: {}",
+            dir.display(),
+            e
+        ))
+    })?;
+
+    let mut certs = HashMap::new();
+    for entry in entries {
+        let cert_path = entry?.path();
+        if cert_path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+            continue;
+        }
+        let stem = match cert_path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let host = match stem.strip_prefix("_.") {
+            Some(parent) => format!("*.{}", parent),
+            None => stem.to_string(),
+        }
+        .to_ascii_lowercase();
+
+        let key_path = cert_path.with_extension("key");
+        certs.insert(host, load_certified_key(&cert_path, &key_path)?);
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, Error> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("certificado {}: {}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "certificado {}: nenhum certificado PEM encontrado",
+            cert_path.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| invalid(format!("chave {}: {}", key_path.display(), e)))?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|e| invalid(format!("chave {}: {}", key_path.display(), e)))?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
@@ -0,0 +1,559 @@
+use crate::buffer::{self, AdaptiveBuffer};
+use crate::config::Config;
+use crate::relay::{self, SHUTDOWN_TIMEOUT};
+use crate::session::{CloseReason, Direction, Session};
+use std::io::{Error, ErrorKind, IoSlice};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+// Cabeçalho de frame do servidor: sem máscara, com até 8 bytes de tamanho.
+const MAX_HEADER_LEN: usize = 10;
+const READ_CHUNK: usize = 512;
+
+pub enum Message {
+    Data(usize),
+    Ping(Vec<u8>),
+    Close,
+    Eof,
+}
+
+struct FrameHeader {
+    opcode: u8,
+    len: u64,
+    mask: [u8; 4],
+    header_len: usize,
+}
+
+// Decodifica os frames mascarados enviados pelo cliente. Cabeçalhos e frames de controle
+// são acumulados em `buf`; dados vão direto para `out`, com o que falta do frame em
+// `remaining`. `read` só espera em leituras do socket que ainda não consumiram nada,
+// então cancelá-lo não perde estado.
+pub struct FrameReader {
+    buf: Vec<u8>,
+    remaining: u64,
+    mask: [u8; 4],
+    mask_offset: usize,
+}
+
+impl FrameReader {
+    pub fn new(pending: Vec<u8>) -> Self {
+        FrameReader {
+            buf: pending,
+            remaining: 0,
+            mask: [0; 4],
+            mask_offset: 0,
+        }
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut R,
+        out: &mut [u8],
+    ) -> Result<Message, Error> {
+        loop {
+            if self.remaining > 0 {
+                let max = out
+                    .len()
+                    .min(self.remaining.min(usize::MAX as u64) as usize);
+                let n = if self.buf.is_empty() {
+                    let n = stream.read(&mut out[..max]).await?;
+                    if n == 0 {
+                        return Err(unexpected_eof());
+                    }
+                    n
+                } else {
+                    let n = max.min(self.buf.len());
+                    out[..n].copy_from_slice(&self.buf[..n]);
+                    self.buf.drain(..n);
+                    n
+                };
+                self.unmask(&mut out[..n]);
+                self.remaining -= n as u64;
+                return Ok(Message::Data(n));
+            }
+
+            let header = match parse_header(&self.buf)? {
+                Some(header) => header,
+                None => {
+                    if !self.fill(stream).await? {
+                        return if self.buf.is_empty() {
+                            Ok(Message::Eof)
+                        } else {
+                            Err(unexpected_eof())
+                        };
+                    }
+                    continue;
+                }
+            };
+
+            match header.opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    self.buf.drain(..header.header_len);
+                    self.remaining = header.len;
+                    self.mask = header.mask;
+                    self.mask_offset = 0;
+                }
+                OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                    let frame_len = header.header_len + header.len as usize;
+                    if self.buf.len() < frame_len {
+                        if !self.fill(stream).await? {
+                            return Err(unexpected_eof());
+                        }
+                        continue;
+                    }
+                    let mut payload: Vec<u8> = self.buf.drain(..frame_len).collect();
+                    payload.drain(..header.header_len);
+                    for (i, byte) in payload.iter_mut().enumerate() {
+                        *byte ^= header.mask[i % 4];
+                    }
+                    match header.opcode {
+                        OPCODE_CLOSE => return Ok(Message::Close),
+                        OPCODE_PING => return Ok(Message::Ping(payload)),
+                        _ => {}
+                    }
+                }
+                opcode => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("opcode WebSocket desconhecido: {:#x}", opcode),
+                    ))
+                }
+            }
+        }
+    }
+
+    async fn fill<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> Result<bool, Error> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = stream.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    fn unmask(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte ^= self.mask[self.mask_offset];
+            self.mask_offset = (self.mask_offset + 1) % 4;
+        }
+    }
+}
+
+fn parse_header(buf: &[u8]) -> Result<Option<FrameHeader>, Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buf[0] & 0x0f;
+    if buf[1] & 0x80 == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "frame WebSocket do cliente sem máscara",
+        ));
+    }
+
+    let (len, len_bytes) = match buf[1] & 0x7f {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 2)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 8)
+        }
+        len => (len as u64, 0),
+    };
+    if opcode >= OPCODE_CLOSE && len > MAX_CONTROL_PAYLOAD {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "frame de controle WebSocket muito grande",
+        ));
+    }
+
+    let mask_start = 2 + len_bytes;
+    if buf.len() < mask_start + 4 {
+        return Ok(None);
+    }
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buf[mask_start..mask_start + 4]);
+
+    Ok(Some(FrameHeader {
+        opcode,
+        len,
+        mask,
+        header_len: mask_start + 4,
+    }))
+}
+
+fn encode_header(opcode: u8, len: usize, out: &mut [u8; MAX_HEADER_LEN]) -> usize {
+    out[0] = 0x80 | opcode;
+    if len < 126 {
+        out[1] = len as u8;
+        2
+    } else if len <= u16::MAX as usize {
+        out[1] = 126;
+        out[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        4
+    } else {
+        out[1] = 127;
+        out[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+        10
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &Mutex<W>,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let mut writer = writer.lock().await;
+    write_frame_to(&mut *writer, opcode, payload).await
+}
+
+// Cabeçalho e payload vão juntos em uma escrita vetorizada, sem copiar o payload para um
+// buffer de frame.
+async fn write_frame_to<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let mut header = [0; MAX_HEADER_LEN];
+    let header_len = encode_header(opcode, payload.len(), &mut header);
+    let (mut header, mut payload) = (&header[..header_len], payload);
+    while !header.is_empty() {
+        let n = writer
+            .write_vectored(&[IoSlice::new(header), IoSlice::new(payload)])
+            .await?;
+        if n == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        let from_header = n.min(header.len());
+        header = &header[from_header..];
+        payload = &payload[n - from_header..];
+    }
+    writer.write_all(payload).await
+}
+
+// Lê até o primeiro payload de dados, respondendo pings no caminho.
+// Retorna None se o cliente fechar antes de enviar dados e um erro TimedOut se nada chegar
+// em `peek_timeout`. O prazo vale só para as leituras: um pong nunca fica pela metade.
+pub async fn read_first_payload<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    reader: &mut FrameReader,
+    session: &Session,
+    buffer_size: usize,
+    peek_timeout: Duration,
+) -> Result<Option<Vec<u8>>, Error> {
+    let deadline = Instant::now() + peek_timeout;
+    let mut buffer = AdaptiveBuffer::new(buffer_size, session);
+    loop {
+        let message = timeout_at(deadline, reader.read(stream, buffer.as_mut()))
+            .await
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    "nenhum dado WebSocket dentro do tempo de espiada",
+                )
+            })?;
+        match message? {
+            Message::Data(n) => return Ok(Some(buffer.as_mut()[..n].to_vec())),
+            Message::Ping(payload) => write_frame_to(stream, OPCODE_PONG, &payload).await?,
+            Message::Close | Message::Eof => return Ok(None),
+        }
+    }
+}
+
+pub async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+    client_stream: S,
+    server_stream: TcpStream,
+    mut reader: FrameReader,
+    session: &Session,
+    config: &Config,
+) -> Result<CloseReason, Error> {
+    let buffer_size = config.buffer_size;
+    let (mut client_read, client_write) = tokio::io::split(client_stream);
+    let (mut server_read, mut server_write) = server_stream.into_split();
+    let client_write = Mutex::new(client_write);
+    let close_sent = AtomicBool::new(false);
+
+    let client_to_server = async {
+        let mut buffer = AdaptiveBuffer::new(buffer_size, session);
+        loop {
+            let read_len = session.chunk_len(Direction::Up, buffer.as_mut().len());
+            let read = reader.read(&mut client_read, &mut buffer.as_mut()[..read_len]);
+            // Mesmo critério de AdaptiveBuffer::read_from; FrameReader::read pode ser cancelado.
+            let message = match timeout(buffer::IDLE_AFTER, read).await {
+                Ok(message) => message,
+                Err(_) => {
+                    buffer.release_idle();
+                    let read_len = session.chunk_len(Direction::Up, buffer.as_mut().len());
+                    reader
+                        .read(&mut client_read, &mut buffer.as_mut()[..read_len])
+                        .await
+                }
+            };
+            match message? {
+                Message::Data(n) => {
+                    session.throttle(Direction::Up, n).await;
+                    server_write.write_all(&buffer.as_mut()[..n]).await?;
+                    session.add_bytes(Direction::Up, n);
+                    buffer.record(n);
+                }
+                Message::Ping(payload) => write_frame(&client_write, OPCODE_PONG, &payload).await?,
+                Message::Close => {
+                    if !close_sent.swap(true, Ordering::SeqCst) {
+                        write_frame(&client_write, OPCODE_CLOSE, &[]).await?;
+                    }
+                    break;
+                }
+                Message::Eof => break,
+            }
+        }
+        server_write.shutdown().await
+    };
+
+    let server_to_client = async {
+        let mut buffer = AdaptiveBuffer::new(buffer_size, session);
+        loop {
+            let bytes_read = buffer
+                .read_from(&mut server_read, |len| {
+                    session.chunk_len(Direction::Down, len)
+                })
+                .await?;
+            if bytes_read == 0 {
+                if !close_sent.swap(true, Ordering::SeqCst) {
+                    write_frame(&client_write, OPCODE_CLOSE, &[]).await?;
+                }
+                break;
+            }
+            session.throttle(Direction::Down, bytes_read).await;
+            write_frame(&client_write, OPCODE_BINARY, &buffer.as_mut()[..bytes_read]).await?;
+            session.add_bytes(Direction::Down, bytes_read);
+            buffer.record(bytes_read);
+        }
+        Ok::<(), Error>(())
+    };
+
+    let result = relay::drive(client_to_server, server_to_client, session, config).await;
+
+    // Mesmo fechamento do relay comum: FIN nos dois lados após linger, reset ou tempo limite.
+    let _ = timeout(SHUTDOWN_TIMEOUT, async {
+        let mut client_write = client_write.lock().await;
+        tokio::join!(client_write.shutdown(), server_write.shutdown())
+    })
+    .await;
+    result
+}
+
+fn unexpected_eof() -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        "conexão encerrada no meio de um frame WebSocket",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    // Frame como um cliente enviaria: sempre com máscara.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = [0; MAX_HEADER_LEN];
+        let header_len = encode_header(opcode, payload.len(), &mut header);
+        let mut frame = header[..header_len].to_vec();
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+        frame
+    }
+
+    // Lê todos os dados até o fim do stream, guardando as mensagens de controle.
+    async fn read_all<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        reader: &mut FrameReader,
+        out_len: usize,
+    ) -> (Vec<u8>, Vec<String>) {
+        let mut data = Vec::new();
+        let mut control = Vec::new();
+        let mut out = vec![0; out_len];
+        loop {
+            match reader.read(stream, &mut out).await.unwrap() {
+                Message::Data(n) => data.extend_from_slice(&out[..n]),
+                Message::Ping(payload) => {
+                    control.push(format!("ping:{}", String::from_utf8_lossy(&payload)))
+                }
+                Message::Close => control.push("close".to_string()),
+                Message::Eof => return (data, control),
+            }
+        }
+    }
+
+    #[test]
+    fn header_lengths() {
+        let short = client_frame(OPCODE_BINARY, &[0; 125]);
+        let header = parse_header(&short).unwrap().unwrap();
+        assert_eq!((header.len, header.header_len), (125, 6));
+
+        let medium = client_frame(OPCODE_BINARY, &[0; 300]);
+        assert_eq!(medium[1] & 0x7f, 126);
+        let header = parse_header(&medium).unwrap().unwrap();
+        assert_eq!((header.len, header.header_len), (300, 8));
+
+        let long = client_frame(OPCODE_BINARY, &vec![0; 70_000]);
+        assert_eq!(long[1] & 0x7f, 127);
+        let header = parse_header(&long).unwrap().unwrap();
+        assert_eq!((header.len, header.header_len), (70_000, 14));
+        assert_eq!(header.mask, MASK);
+    }
+
+    #[test]
+    fn incomplete_headers_wait_for_more() {
+        let long = client_frame(OPCODE_BINARY, &vec![0; 70_000]);
+        for len in [0, 1, 2, 9, 13] {
+            assert!(parse_header(&long[..len]).unwrap().is_none(), "len {}", len);
+        }
+        assert!(parse_header(&long[..14]).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_unmasked_and_oversized_control_frames() {
+        let mut header = [0; MAX_HEADER_LEN];
+        let header_len = encode_header(OPCODE_BINARY, 3, &mut header);
+        let unmasked = [&header[..header_len], b"abc"].concat();
+        assert!(parse_header(&unmasked).is_err());
+
+        let big_ping = client_frame(OPCODE_PING, &[0; 126]);
+        assert!(parse_header(&big_ping).is_err());
+    }
+
+    #[test]
+    fn server_headers_use_shortest_length() {
+        for (len, header_len) in [(0, 2), (125, 2), (126, 4), (65_535, 4), (65_536, 10)] {
+            let mut out = [0; MAX_HEADER_LEN];
+            assert_eq!(
+                encode_header(OPCODE_BINARY, len, &mut out),
+                header_len,
+                "len {}",
+                len
+            );
+            assert_eq!(out[0], 0x80 | OPCODE_BINARY);
+        }
+    }
+
+    // Aceita no máximo 3 bytes por escrita, para exercitar escritas parciais.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<Result<usize, Error>> {
+            let n = buf.len().min(3);
+            self.get_mut().0.extend_from_slice(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> std::task::Poll<Result<usize, Error>> {
+            let joined: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            self.poll_write(cx, &joined)
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_survive_short_writes() {
+        let payload: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let mut out = Trickle(Vec::new());
+        write_frame_to(&mut out, OPCODE_BINARY, &payload)
+            .await
+            .unwrap();
+        write_frame_to(&mut out, OPCODE_CLOSE, &[]).await.unwrap();
+        let mut expected = vec![0x80 | OPCODE_BINARY, 126, 1, 44];
+        expected.extend_from_slice(&payload);
+        expected.extend_from_slice(&[0x80 | OPCODE_CLOSE, 0]);
+        assert_eq!(out.0, expected);
+    }
+
+    #[tokio::test]
+    async fn unmasks_data_across_frames_and_reads() {
+        let payload: Vec<u8> = (0..70_000u32).map(|i| (i % 251) as u8).collect();
+        let mut wire = client_frame(OPCODE_BINARY, &payload[..300]);
+        wire.extend(client_frame(OPCODE_CONTINUATION, &payload[300..]));
+
+        // Entregue em pedaços que cortam cabeçalhos e payloads no meio.
+        let (first, rest) = wire.split_at(5);
+        let (second, third) = rest.split_at(400);
+        let mut stream = first.chain(second).chain(third);
+        let mut reader = FrameReader::new(Vec::new());
+        let (data, control) = read_all(&mut stream, &mut reader, 1000).await;
+        assert_eq!(data, payload);
+        assert!(control.is_empty());
+    }
+
+    #[tokio::test]
+    async fn control_frame_split_across_reads() {
+        let mut wire = client_frame(OPCODE_BINARY, b"SSH-2.0-x\r\n");
+        let ping = client_frame(OPCODE_PING, b"oi");
+        wire.extend_from_slice(&ping[..4]);
+        let tail = [&ping[4..], &client_frame(OPCODE_CLOSE, &[])[..]].concat();
+
+        // Parte do frame inicial já veio junto com o cabeçalho HTTP.
+        let mut reader = FrameReader::new(wire[..3].to_vec());
+        let mut stream = (&wire[3..]).chain(&tail[..]);
+        let (data, control) = read_all(&mut stream, &mut reader, 64).await;
+        assert_eq!(data, b"SSH-2.0-x\r\n");
+        assert_eq!(control, ["ping:oi", "close"]);
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_frame_is_an_error() {
+        let wire = client_frame(OPCODE_BINARY, b"abcdef");
+        let mut stream = &wire[..wire.len() - 2];
+        let mut reader = FrameReader::new(Vec::new());
+        let mut out = [0; 64];
+        assert!(matches!(
+            reader.read(&mut stream, &mut out).await.unwrap(),
+            Message::Data(4)
+        ));
+        let e = reader.read(&mut stream, &mut out).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
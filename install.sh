@@ -1,18 +1,21 @@
 #!/usr/bin/env bash
-# Instalação Rusty Proxy (Opção B - EnvironmentFile) - corrigido
+# Instalação Rusty Proxy - um único serviço systemd lendo /etc/rustyproxy/config.toml
 
 set -Eeuo pipefail
 IFS=$'\n\t'
 
-TOTAL_STEPS=10
+TOTAL_STEPS=9
 CURRENT_STEP=0
 
 LOG_FILE="/var/log/rustyproxy-install.log"
 
 RUSTY_DIR="/opt/rustyproxy"
 ENV_DIR="/etc/rustyproxy"
-UNIT_TEMPLATE="/etc/systemd/system/proxy@.service"
+UNIT_FILE="/etc/systemd/system/rustyproxy.service"
+CONFIG_FILE="${ENV_DIR}/config.toml"
 PORTS_FILE="${RUSTY_DIR}/ports"
+# Instalações antigas tinham um serviço proxy@PORTA por porta.
+LEGACY_TEMPLATE="/etc/systemd/system/proxy@.service"
 
 CLONE_DIR="/root/RustyProxyOnly"
 REPO_URL='https://github.com/PhoenixxZ2023/RustyProxyOnly.git'
@@ -96,18 +99,16 @@ ensure_rust() {
   command -v cargo >/dev/null 2>&1 || error_exit "Falha ao disponibilizar cargo após rustup."
 }
 
-install_unit_template() {
-  # template systemd para proxy@PORT.service
-  cat >"${UNIT_TEMPLATE}" <<'EOF'
+install_unit() {
+  cat >"${UNIT_FILE}" <<'EOF'
 [Unit]
-Description=RustyProxy instance on port %i
+Description=RustyProxy
 After=network-online.target
 Wants=network-online.target
 
 [Service]
 Type=simple
-EnvironmentFile=/etc/rustyproxy/proxy%i.env
-ExecStart=/opt/rustyproxy/proxy --port %i --status ${STATUS}
+ExecStart=/opt/rustyproxy/proxy --config /etc/rustyproxy/config.toml --admin-listen /run/rustyproxy/admin.sock
 Restart=always
 RestartSec=2
 LimitNOFILE=1048576
@@ -116,6 +117,10 @@ LimitNOFILE=1048576
 NoNewPrivileges=true
 PrivateTmp=true
 ProtectSystem=strict
+LogsDirectory=rustyproxy
+# Só este serviço usa /run/rustyproxy, onde fica o socket da API de administração.
+RuntimeDirectory=rustyproxy
+ReadWritePaths=-/etc/rustyproxy
 ProtectHome=true
 ProtectKernelTunables=true
 ProtectKernelModules=true
@@ -132,6 +137,51 @@ EOF
   run systemctl daemon-reload
 }
 
+# Para os serviços proxy@PORTA de instalações antigas; as portas continuam em PORTS_FILE
+# e passam para o config.toml.
+migrate_legacy_units() {
+  [[ -f "${LEGACY_TEMPLATE}" ]] || return 0
+
+  local port
+  while IFS='|' read -r port _; do
+    [[ "${port:-}" =~ ^[0-9]+$ ]] || continue
+    run systemctl disable --now "proxy@${port}.service" || true
+    rm -f "${ENV_DIR}/proxy${port}.env"
+  done < "${PORTS_FILE}"
+
+  rm -f "${LEGACY_TEMPLATE}"
+  run systemctl daemon-reload
+}
+
+# Só cria o arquivo na primeira instalação; edições manuais são preservadas.
+install_config() {
+  [[ -f "${CONFIG_FILE}" ]] && return 0
+
+  cat >"${CONFIG_FILE}" <<'EOF'
+# Configuração do RustyProxy. Veja o README para todas as opções (limites, TLS,
+# regras, cotas, autenticação...). As portas criadas pelo menu ficam no bloco
+# gerado no fim do arquivo; mantenha-o por último.
+
+[backends]
+ssh = "127.0.0.1:22"
+openvpn = "127.0.0.1:1194"
+
+[[rules]]
+backend = "ssh"
+contains = "SSH"
+
+[[rules]]
+backend = "ssh"
+empty = true
+
+[[rules]]
+backend = "openvpn"
+protocol = "openvpn"
+
+EOF
+  chmod 600 "${CONFIG_FILE}"
+}
+
 install_cli() {
   cat >/usr/local/bin/rustyproxyctl <<'EOF'
 #!/usr/bin/env bash
@@ -139,13 +189,21 @@ set -Eeuo pipefail
 IFS=$'\n\t'
 
 PORTS_FILE="/opt/rustyproxy/ports"
+ADMIN_SOCKET="/run/rustyproxy/admin.sock"
+
+admin() {
+  curl -fsS --unix-socket "$ADMIN_SOCKET" "$@"
+}
 
 usage() {
   cat <<USAGE
 Uso:
   rustyproxyctl list        # lista portas + status salvo
-  rustyproxyctl status      # mostra estado systemd de cada porta
-  rustyproxyctl logs <port> # logs do serviço (últimas 200 linhas)
+  rustyproxyctl status      # mostra o estado do serviço
+  rustyproxyctl logs        # logs do serviço (últimas 200 linhas)
+  rustyproxyctl sessions    # sessões abertas (JSON)
+  rustyproxyctl kill <id>   # encerra uma sessão
+  rustyproxyctl kill-ip <ip> # encerra todas as sessões do IP
 USAGE
 }
 
@@ -162,22 +220,26 @@ case "$cmd" in
     cat "$PORTS_FILE"
     ;;
   status)
-    if [[ ! -s "$PORTS_FILE" ]]; then
-      echo "Nenhuma porta cadastrada."
-      exit 0
-    fi
-    while IFS='|' read -r port status; do
-      [[ -n "${port:-}" ]] || continue
-      svc="proxy@${port}.service"
-      state="$(systemctl is-active "$svc" 2>/dev/null || true)"
-      enabled="$(systemctl is-enabled "$svc" 2>/dev/null || true)"
-      printf "port=%s  active=%s  enabled=%s  status=%s\n" "$port" "$state" "$enabled" "${status:-}"
-    done < "$PORTS_FILE"
+    systemctl status rustyproxy.service --no-pager || true
     ;;
   logs)
-    port="${2:-}"
-    [[ "$port" =~ ^[0-9]+$ ]] || { echo "Informe uma porta válida."; exit 1; }
-    journalctl -u "proxy@${port}.service" -n 200 --no-pager
+    journalctl -u rustyproxy.service -n 200 --no-pager
+    ;;
+  sessions)
+    admin localhost/sessions
+    echo
+    ;;
+  kill)
+    id="${2:-}"
+    [[ "$id" =~ ^[0-9]+$ ]] || { echo "Informe o id da sessão."; exit 1; }
+    admin -X DELETE "localhost/sessions/${id}"
+    echo
+    ;;
+  kill-ip)
+    ip="${2:-}"
+    [[ "$ip" =~ ^[0-9A-Fa-f.:]+$ ]] || { echo "Informe um IP válido."; exit 1; }
+    admin -X DELETE "localhost/sessions?peer=${ip}"
+    echo
     ;;
   *)
     usage
@@ -206,7 +268,7 @@ install_rustyproxy() {
 
   run mkdir -p "${RUSTY_DIR}" "${ENV_DIR}"
 
-  # instala menu (o menu deve ser o da Opção B)
+  # instala menu
   [[ -f "${CLONE_DIR}/menu.sh" ]] || error_exit "menu.sh não encontrado no repo."
   run install -m 0755 "${CLONE_DIR}/menu.sh" "${RUSTY_DIR}/menu"
 
@@ -264,8 +326,10 @@ main() {
   install_rustyproxy
   increment_step
 
-  show_progress "INSTALANDO TEMPLATE SYSTEMD (proxy@PORT)..."
-  install_unit_template
+  show_progress "INSTALANDO SERVIÇO SYSTEMD (rustyproxy)..."
+  migrate_legacy_units
+  install_unit
+  install_config
   increment_step
 
   show_progress "INSTALANDO rustyproxyctl (CLI)..."
@@ -276,6 +340,10 @@ main() {
   setup_links
   increment_step
 
+  show_progress "APLICANDO CONFIGURAÇÃO..."
+  run "${RUSTY_DIR}/menu" sync
+  increment_step
+
   show_progress "LIMPANDO..."
   cleanup
   increment_step
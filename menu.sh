@@ -1,5 +1,5 @@
 #!/usr/bin/env bash
-# RUSTYPROXY MANAGER (Opção B - EnvironmentFile) - final
+# RUSTYPROXY MANAGER - um único serviço (rustyproxy.service) lendo /etc/rustyproxy/config.toml
 
 set -Eeuo pipefail
 IFS=$'\n\t'
@@ -7,8 +7,15 @@ IFS=$'\n\t'
 PORTS_FILE="/opt/rustyproxy/ports"
 PROXY_BIN="/opt/rustyproxy/proxy"
 
-UNIT_TEMPLATE="/etc/systemd/system/proxy@.service"
+UNIT_NAME="rustyproxy.service"
+UNIT_FILE="/etc/systemd/system/${UNIT_NAME}"
 ENV_DIR="/etc/rustyproxy"
+CONFIG_FILE="${ENV_DIR}/config.toml"
+
+# As portas do menu ficam entre estes marcadores, no fim do config.toml; o resto do
+# arquivo é livre para edição manual.
+BLOCK_BEGIN="# >>> portas do menu (gerado pelo rustyproxy; não edite este bloco) >>>"
+BLOCK_END="# <<< portas do menu <<<"
 
 RED="\033[1;31m"
 GREEN="\033[1;32m"
@@ -24,7 +31,8 @@ if [[ "${EUID}" -ne 0 ]]; then
 fi
 
 [[ -x "${PROXY_BIN}" ]] || { echo -e "${RED}Binário não encontrado/executável: ${PROXY_BIN}${RESET}"; exit 1; }
-[[ -f "${UNIT_TEMPLATE}" ]] || { echo -e "${RED}Template systemd não encontrado: ${UNIT_TEMPLATE}${RESET}"; exit 1; }
+[[ -f "${UNIT_FILE}" ]] || { echo -e "${RED}Serviço systemd não encontrado: ${UNIT_FILE}${RESET}"; exit 1; }
+[[ -f "${CONFIG_FILE}" ]] || { echo -e "${RED}Configuração não encontrada: ${CONFIG_FILE}${RESET}"; exit 1; }
 
 mkdir -p "$(dirname "$PORTS_FILE")" "$ENV_DIR"
 touch "$PORTS_FILE"
@@ -36,9 +44,6 @@ valid_port() {
   (( $1 >= 1 && $1 <= 65535 )) || return 1
 }
 
-svc_name() { echo "proxy@${1}.service"; }
-env_path() { echo "${ENV_DIR}/proxy${1}.env"; }
-
 is_port_in_use() {
   local port="$1"
   if command -v ss >/dev/null 2>&1; then
@@ -52,120 +57,157 @@ is_port_in_use() {
   return 1
 }
 
-# Escreve STATUS de forma segura para EnvironmentFile:
-# STATUS='...'
-write_env_file() {
-  local port="$1"
-  local status="$2"
-  local p
-  p="$(env_path "$port")"
-
+clean_status() {
+  local status="$1"
   status="${status//$'\r'/ }"
   status="${status//$'\n'/ }"
-  status="${status:0:128}"
-
-  # escape para aspas simples: ' -> '\'' (fecha, escapa, reabre)
-  local esc
-  esc="$(printf "%s" "$status" | sed "s/'/'\\\\''/g")"
+  status="${status//|/ }"
+  printf "%s" "${status:0:128}"
+}
 
-  cat >"$p" <<EOF
-# Generated by RustyProxy Manager
-STATUS='$esc'
-EOF
-  chmod 600 "$p"
+# String TOML entre aspas duplas: escapa \ e ".
+toml_string() {
+  local value="$1"
+  value="${value//\\/\\\\}"
+  value="${value//\"/\\\"}"
+  printf '"%s"' "$value"
 }
 
 upsert_port_record() {
   local port="$1"
   local status="$2"
-  if grep -qE "^${port}\|" "$PORTS_FILE"; then
-    sed -i "s/^${port}|.*/${port}|${status}/" "$PORTS_FILE"
-  else
-    echo "${port}|${status}" >> "$PORTS_FILE"
-  fi
+  local tmp
+  tmp="$(mktemp "${PORTS_FILE}.XXXXXX")"
+  grep -vE "^${port}\|" "$PORTS_FILE" > "$tmp" || true
+  echo "${port}|${status}" >> "$tmp"
+  chmod 600 "$tmp"
+  mv "$tmp" "$PORTS_FILE"
 }
 
 remove_port_record() {
   local port="$1"
-  sed -i "/^${port}\|/d" "$PORTS_FILE" || true
+  sed -i "/^${port}|/d" "$PORTS_FILE" || true
+}
+
+has_port_record() {
+  grep -qE "^${1}\|" "$PORTS_FILE"
+}
+
+# Reescreve o bloco de portas do config.toml a partir de PORTS_FILE. A configuração
+# nova só substitui a atual se o binário a aceitar.
+write_config() {
+  local tmp
+  tmp="$(mktemp "${CONFIG_FILE}.XXXXXX")"
+  awk -v begin="$BLOCK_BEGIN" -v end="$BLOCK_END" '
+    $0 == begin { skip = 1; next }
+    $0 == end { skip = 0; next }
+    !skip
+  ' "$CONFIG_FILE" > "$tmp"
+
+  {
+    echo "$BLOCK_BEGIN"
+    while IFS='|' read -r port status; do
+      [[ -n "${port:-}" ]] || continue
+      valid_port "$port" || continue
+      echo "[[listeners]]"
+      echo "port = ${port}"
+      echo "status = $(toml_string "${status:-@RustyManager}")"
+      echo
+    done < "$PORTS_FILE"
+    echo "$BLOCK_END"
+  } >> "$tmp"
+  chmod 600 "$tmp"
+
+  # Sem nenhum listener não há o que validar: o serviço só fica parado.
+  if grep -q '^\[\[listeners\]\]' "$tmp" && ! "$PROXY_BIN" --config "$tmp" --check-config >/dev/null; then
+    rm -f "$tmp"
+    return 1
+  fi
+  mv "$tmp" "$CONFIG_FILE"
+}
+
+# Aplica PORTS_FILE ao config.toml e reinicia o serviço (ou o para, sem portas).
+apply_config() {
+  if ! write_config; then
+    echo -e "${RED}⛔️ CONFIGURAÇÃO RECUSADA; NADA FOI ALTERADO. VEJA O ERRO ACIMA.${RESET}"
+    return 1
+  fi
+
+  if ! grep -q '^\[\[listeners\]\]' "$CONFIG_FILE"; then
+    systemctl disable --now "$UNIT_NAME" >/dev/null 2>&1 || true
+    return 0
+  fi
+  systemctl enable "$UNIT_NAME" >/dev/null 2>&1
+  systemctl restart "$UNIT_NAME"
 }
 
 add_proxy_port() {
   local port="$1"
-  local status="${2:-@RustyManager}"
+  local status
+  status="$(clean_status "${2:-@RustyManager}")"
 
   valid_port "$port" || { echo -e "${RED}Porta inválida (1-65535).${RESET}"; return; }
 
+  if has_port_record "$port"; then
+    echo -e "${RED}⛔️ A PORTA $port JÁ ESTÁ CADASTRADA.${RESET}"
+    return
+  fi
   if is_port_in_use "$port"; then
     echo -e "${RED}⛔️ A PORTA $port JÁ ESTÁ EM USO.${RESET}"
     return
   fi
 
-  write_env_file "$port" "$status"
-
-  systemctl enable "$(svc_name "$port")" >/dev/null
-  systemctl restart "$(svc_name "$port")"
-
   upsert_port_record "$port" "$status"
+  if ! apply_config; then
+    remove_port_record "$port"
+    return
+  fi
   echo -e "${GREEN}✅ PORTA $port ATIVADA COM SUCESSO.${RESET}"
 }
 
 del_proxy_port() {
   local port="$1"
   valid_port "$port" || { echo -e "${RED}Porta inválida (1-65535).${RESET}"; return; }
+  has_port_record "$port" || { echo -e "${RED}A PORTA $port NÃO ESTÁ CADASTRADA NO MENU.${RESET}"; return; }
 
-  systemctl stop "$(svc_name "$port")" >/dev/null 2>&1 || true
-  systemctl disable "$(svc_name "$port")" >/dev/null 2>&1 || true
-
-  rm -f "$(env_path "$port")"
+  local previous
+  previous="$(grep -E "^${port}\|" "$PORTS_FILE")"
   remove_port_record "$port"
+  if ! apply_config; then
+    echo "$previous" >> "$PORTS_FILE"
+    return
+  fi
 
   echo -e "${GREEN}✅ PORTA $port DESATIVADA/REMOVIDA COM SUCESSO.${RESET}"
 }
 
 update_proxy_status() {
   local port="$1"
-  local new_status="$2"
+  local new_status
+  new_status="$(clean_status "$2")"
 
   valid_port "$port" || { echo -e "${RED}Porta inválida (1-65535).${RESET}"; return; }
   [[ -n "${new_status}" ]] || { echo -e "${RED}Status não pode ser vazio.${RESET}"; return; }
+  has_port_record "$port" || { echo -e "${RED}A PORTA $port NÃO ESTÁ CADASTRADA NO MENU.${RESET}"; return; }
 
-  write_env_file "$port" "$new_status"
-
-  # se não existir serviço habilitado ainda, enable+start
-  systemctl restart "$(svc_name "$port")" >/dev/null 2>&1 || {
-    systemctl enable "$(svc_name "$port")" >/dev/null
-    systemctl restart "$(svc_name "$port")"
-  }
-
+  local previous
+  previous="$(grep -E "^${port}\|" "$PORTS_FILE")"
   upsert_port_record "$port" "$new_status"
+  if ! apply_config; then
+    upsert_port_record "$port" "${previous#*|}"
+    return
+  fi
+
   echo -e "${YELLOW}🔃 STATUS DA PORTA $port ATUALIZADO PARA '${new_status}'.${RESET}"
   sleep 1
 }
 
 restart_all_proxies() {
-  if [[ ! -s "$PORTS_FILE" ]]; then
-    echo "NENHUMA PORTA ENCONTRADA PARA REINICIAR."
-    return
-  fi
-
-  echo "🔃 REINICIANDO TODAS AS PORTAS DO PROXY..."
+  echo "🔃 REINICIANDO O PROXY..."
   sleep 1
 
-  while IFS='|' read -r port status; do
-    [[ -n "${port:-}" ]] || continue
-    valid_port "$port" || continue
-
-    # garante env consistente
-    write_env_file "$port" "${status:-@RustyManager}"
-
-    systemctl restart "$(svc_name "$port")" >/dev/null 2>&1 || {
-      systemctl enable "$(svc_name "$port")" >/dev/null
-      systemctl restart "$(svc_name "$port")"
-    }
-  done < "$PORTS_FILE"
-
-  echo -e "${GREEN}✅ TODAS AS PORTAS FORAM REINICIADAS COM SUCESSO.${RESET}"
+  apply_config || return
+  echo -e "${GREEN}✅ PROXY REINICIADO COM SUCESSO.${RESET}"
   sleep 1
 }
 
@@ -174,22 +216,11 @@ uninstall_rustyproxy() {
   sleep 1
   clear
 
-  # Para tudo que estiver listado
-  if [[ -s "$PORTS_FILE" ]]; then
-    while IFS='|' read -r port _; do
-      [[ -n "${port:-}" ]] || continue
-      valid_port "$port" || continue
-      systemctl stop "$(svc_name "$port")" >/dev/null 2>&1 || true
-      systemctl disable "$(svc_name "$port")" >/dev/null 2>&1 || true
-      rm -f "$(env_path "$port")"
-    done < "$PORTS_FILE"
-  fi
-
-  # Remove envs órfãos e template (instalado pelo install.sh)
-  rm -rf "$ENV_DIR" || true
-  rm -f "$UNIT_TEMPLATE" || true
+  systemctl disable --now "$UNIT_NAME" >/dev/null 2>&1 || true
+  rm -f "$UNIT_FILE" || true
   systemctl daemon-reload
 
+  rm -rf "$ENV_DIR" || true
   rm -rf /opt/rustyproxy
   rm -f /usr/local/bin/rustyproxy /usr/local/bin/rustyproxyctl || true
 
@@ -281,6 +312,12 @@ show_menu() {
   esac
 }
 
+# Uso não interativo pelo install.sh: regenera o bloco de portas e reinicia o serviço.
+if [[ "${1:-}" == "sync" ]]; then
+  apply_config && exit 0
+  exit 1
+fi
+
 while true; do
   show_menu
 done